@@ -10,11 +10,22 @@ use std::io::{Read, Write};
 lazy_static! {
 	pub static ref data: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
 	pub static ref path: Mutex<Vec<String>> = Mutex::new(Vec::new());
+	pub static ref last_status: Mutex<i32> = Mutex::new(0);
+	// Names that stand for a longer command, from `alias name=value`
+	pub static ref aliases: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+}
+
+pub type CommandFn = fn(Vec<String>, String, Option<Receiver<i16>>, &mut Io) -> Result<(), String>;
+
+pub struct Io {
+	pub stdout: Box<dyn Write + Send>,
+	// Lets a builtin report an exit status other than 0 while still succeeding
+	pub exit_code: Option<i32>,
 }
 
 #[derive(Clone)]
 pub struct Command<'a> {
-	pub func: &'a fn(Vec<String>, String, Option<Receiver<i16>>) -> Result<(), String>,
+	pub func: &'a CommandFn,
 	pub name: String,
 	pub help: String,
 }
@@ -22,104 +33,160 @@ pub struct Command<'a> {
 pub fn create_commands() -> Vec<Command<'static>> {
 	let mut cmds: Vec<Command<'static>> = Vec::new();
 	cmds.push(Command {
-		func: &(help_command as fn(Vec<String>, String, Option<Receiver<i16>>) -> Result<(), String>),
+		func: &(help_command as CommandFn),
 		name: "help".to_string(),
 		help: "Shows this help menu".to_string(),
 	});
 	crate::debug(format!("init {}", cmds.last().unwrap().name));
 	cmds.push(Command {
-		func: &(echo as fn(Vec<String>, String, Option<Receiver<i16>>) -> Result<(), String>),
+		func: &(echo as CommandFn),
 		name: "echo".to_string(),
 		help: "Echos args to stdout".to_string(),
 	});
 	crate::debug(format!("init {}", cmds.last().unwrap().name));
 	cmds.push(Command {
-		func: &(close as fn(Vec<String>, String, Option<Receiver<i16>>) -> Result<(), String>),
+		func: &(close as CommandFn),
 		name: "exit".to_string(),
-		help: "Exits the terminal".to_string(),
+		help: "Exits the terminal with the given code (default: last exit code)".to_string(),
 	});
 	crate::debug(format!("init {}", cmds.last().unwrap().name));
 	cmds.push(Command {
-		func: &(change_directory as fn(Vec<String>, String, Option<Receiver<i16>>) -> Result<(), String>),
+		func: &(change_directory as CommandFn),
 		name: "cd".to_string(),
 		help: "Change the current directory".to_string(),
 	});
 	crate::debug(format!("init {}", cmds.last().unwrap().name));
 	cmds.push(Command {
-		func: &(set_variable as fn(Vec<String>, String, Option<Receiver<i16>>) -> Result<(), String>),
+		func: &(set_variable as CommandFn),
 		name: "set".to_string(),
 		help: "Set a variable to a value".to_string(),
 	});
 	crate::debug(format!("init {}", cmds.last().unwrap().name));
 	cmds.push(Command {
-		func: &(list_variables as fn(Vec<String>, String, Option<Receiver<i16>>) -> Result<(), String>),
+		func: &(list_variables as CommandFn),
 		name: "list".to_string(),
 		help: "Lists the currently defined variables".to_string(),
 	});
 	crate::debug(format!("init {}", cmds.last().unwrap().name));
 	cmds.push(Command {
-		func: &(list_directory as fn(Vec<String>, String, Option<Receiver<i16>>) -> Result<(), String>),
+		func: &(list_directory as CommandFn),
 		name: "ls".to_string(),
 		help: "Lists the current directory".to_string(),
 	});
 	crate::debug(format!("init {}", cmds.last().unwrap().name));
 	cmds.push(Command {
-		func: &(update_path as fn(Vec<String>, String, Option<Receiver<i16>>) -> Result<(), String>),
+		func: &(update_path as CommandFn),
 		name: "reload-path".to_string(),
 		help: "Reloads PATH".to_string(),
 	});
 	crate::debug(format!("init {}", cmds.last().unwrap().name));
 	cmds.push(Command {
-		func: &(find_path as fn(Vec<String>, String, Option<Receiver<i16>>) -> Result<(), String>),
+		func: &(find_path as CommandFn),
 		name: "which".to_string(),
 		help: "Finds an executable in PATH".to_string(),
 	});
 	crate::debug(format!("init {}", cmds.last().unwrap().name));
 	cmds.push(Command {
-		func: &(make_dir as fn(Vec<String>, String, Option<Receiver<i16>>) -> Result<(), String>),
+		func: &(make_dir as CommandFn),
 		name: "mkdir".to_string(),
 		help: "Make a directory".to_string(),
 	});
 	crate::debug(format!("init {}", cmds.last().unwrap().name));
 	cmds.push(Command {
-		func: &(remove as fn(Vec<String>, String, Option<Receiver<i16>>) -> Result<(), String>),
+		func: &(remove as CommandFn),
 		name: "rm".to_string(),
 		help: "Delete a file/directory (recursive)".to_string(),
 	});
 	crate::debug(format!("init {}", cmds.last().unwrap().name));
 	cmds.push(Command {
-		func: &(copy as fn(Vec<String>, String, Option<Receiver<i16>>) -> Result<(), String>),
+		func: &(copy as CommandFn),
 		name: "cp".to_string(),
 		help: "Copy a file/directory (recursive)".to_string(),
 	});
 	crate::debug(format!("init {}", cmds.last().unwrap().name));
+	cmds.push(Command {
+		func: &(list_jobs as CommandFn),
+		name: "jobs".to_string(),
+		help: "Lists background and stopped jobs".to_string(),
+	});
+	crate::debug(format!("init {}", cmds.last().unwrap().name));
+	cmds.push(Command {
+		func: &(foreground as CommandFn),
+		name: "fg".to_string(),
+		help: "Brings a job to the foreground".to_string(),
+	});
+	crate::debug(format!("init {}", cmds.last().unwrap().name));
+	cmds.push(Command {
+		func: &(background as CommandFn),
+		name: "bg".to_string(),
+		help: "Continues a stopped job in the background".to_string(),
+	});
+	crate::debug(format!("init {}", cmds.last().unwrap().name));
+	cmds.push(Command {
+		func: &(kill as CommandFn),
+		name: "kill".to_string(),
+		help: "Sends a signal (default: TERM) to jobs or processes".to_string(),
+	});
+	crate::debug(format!("init {}", cmds.last().unwrap().name));
+	cmds.push(Command {
+		func: &(complete as CommandFn),
+		name: "complete".to_string(),
+		help: "Sets how a command's arguments are completed (-W words, -f files, -d directories, -C program, -r removes)".to_string(),
+	});
+	crate::debug(format!("init {}", cmds.last().unwrap().name));
+	cmds.push(Command {
+		func: &(history as CommandFn),
+		name: "history".to_string(),
+		help: "Lists previous commands (optionally matching a filter), -d {n} deletes one, -c clears all".to_string(),
+	});
+	crate::debug(format!("init {}", cmds.last().unwrap().name));
+	cmds.push(Command {
+		func: &(source as CommandFn),
+		name: "source".to_string(),
+		help: "Runs the commands in a file in this session, the startup file when none is given".to_string(),
+	});
+	crate::debug(format!("init {}", cmds.last().unwrap().name));
+	cmds.push(Command {
+		func: &(alias as CommandFn),
+		name: "alias".to_string(),
+		help: "Lists aliases, or makes a name run a command with {name}={command}".to_string(),
+	});
+	crate::debug(format!("init {}", cmds.last().unwrap().name));
+	cmds.push(Command {
+		func: &(unalias as CommandFn),
+		name: "unalias".to_string(),
+		help: "Removes aliases, -a removes all".to_string(),
+	});
+	crate::debug(format!("init {}", cmds.last().unwrap().name));
 	return cmds;
 }
 
-fn help_command(_: Vec<String>, _: String, rv: Option<Receiver<i16>>) -> Result<(), String> {
-	let mut commands = create_commands();
-	commands.sort_by_key(|x| x.name.clone());
+fn help_command(_: Vec<String>, _: String, rv: Option<Receiver<i16>>, io: &mut Io) -> Result<(), String> {
+	// User-defined functions are listed with the builtins
+	let mut commands: Vec<(String, String)> = create_commands().into_iter().map(|x| (x.name, x.help)).collect();
+	commands.extend(crate::functions::describe());
+	commands.sort_by_key(|x| x.0.clone());
 
 	let mut longest_command: usize = 0;
-	for cmd in commands.iter() {
-		if cmd.name.len() > longest_command {
-			longest_command = cmd.name.len();
+	for (name, _) in commands.iter() {
+		if name.len() > longest_command {
+			longest_command = name.len();
 		}
 	}
 
 	if rv.is_some() {
 		let channel = rv.unwrap();
-		for cmd in commands {
+		for (name, help) in commands {
 			if let Ok(o) = channel.try_recv() {
 				if o == 1 {
 					break;
 				}
 			}
-			println!("{}{}{}", cmd.name, " ".repeat(longest_command-cmd.name.len()+2), cmd.help);
+			writeln!(io.stdout, "{}{}{}", name, " ".repeat(longest_command-name.len()+2), help).map_err(|e| e.to_string())?;
 		}
 	} else {
-		for cmd in commands {
-			println!("{}{}{}", cmd.name, " ".repeat(longest_command-cmd.name.len()+2), cmd.help);
+		for (name, help) in commands {
+			writeln!(io.stdout, "{}{}{}", name, " ".repeat(longest_command-name.len()+2), help).map_err(|e| e.to_string())?;
 		}
 	}
 
@@ -127,23 +194,29 @@ fn help_command(_: Vec<String>, _: String, rv: Option<Receiver<i16>>) -> Result<
 	return Ok(());
 }
 
-fn echo(args: Vec<String>, args_string: String, _: Option<Receiver<i16>>) -> Result<(), String> {
+fn echo(args: Vec<String>, args_string: String, _: Option<Receiver<i16>>, io: &mut Io) -> Result<(), String> {
 	if args.len() == 1 {
-		println!("Syntax: echo {{input}}");
+		writeln!(io.stdout, "Syntax: echo {{input}}").map_err(|e| e.to_string())?;
 		return Ok(());
 	}
-	println!("{}", args_string);
+	writeln!(io.stdout, "{}", args_string).map_err(|e| e.to_string())?;
 	return Ok(());
 }
 
-fn close(_: Vec<String>, _: String, _: Option<Receiver<i16>>) -> Result<(), String> {
-	exit(0);
+fn close(args: Vec<String>, _: String, _: Option<Receiver<i16>>, _: &mut Io) -> Result<(), String> {
+	if args.len() > 1 {
+		match args[1].parse::<i32>() {
+			Ok(o) => exit(o),
+			Err(_) => return Err(format!("{} is not a valid exit code", args[1])),
+		}
+	}
+	exit(*last_status.lock().unwrap());
 }
 
-fn change_directory(args: Vec<String>, _: String, _: Option<Receiver<i16>>) -> Result<(), String> {
+fn change_directory(args: Vec<String>, _: String, _: Option<Receiver<i16>>, io: &mut Io) -> Result<(), String> {
 
 	if args.len() == 1 {
-		println!("Syntax: cd {{directory}}");
+		writeln!(io.stdout, "Syntax: cd {{directory}}").map_err(|e| e.to_string())?;
 		return Ok(());
 	}
 	let p = Path::new(&args[1]);
@@ -158,32 +231,22 @@ fn change_directory(args: Vec<String>, _: String, _: Option<Receiver<i16>>) -> R
 	return Ok(());
 }
 
-fn set_variable(args: Vec<String>, _: String, rv: Option<Receiver<i16>>) -> Result<(), String> {
+fn set_variable(args: Vec<String>, _: String, _: Option<Receiver<i16>>, io: &mut Io) -> Result<(), String> {
 
 	if args.len() != 3 {
-		println!("Syntax: set {{var_name}} {{var_data}}");
+		writeln!(io.stdout, "Syntax: set {{var_name}} {{var_data}}").map_err(|e| e.to_string())?;
 		return Ok(());
 	}
-	if args[1].chars().any(|x| x.to_string() == " ") || args[1] == "" {
+	if args[1].is_empty() {
+		return Err("Name cannot be empty".to_string());
+	}
+	if args[1].chars().any(|x| x.to_string() == " ") {
 		return Err("Name cannot have whitespace".to_string());
 	}
-	let mut d;
-	if rv.is_some() {
-		let channel = rv.unwrap();
-		loop {
-			if let Ok(o) = channel.try_recv() {
-				if o == 1 {
-					return Err("Could not aquire variable mutex".to_string());
-				}
-			}
-			if let Ok(o) = data.try_lock() {
-				d = o;
-				break;
-			}
-		}
-	} else {
-		d = data.lock().unwrap();
+	if crate::functions::set(args[1].trim(), &args[2]) {
+		return Ok(());
 	}
+	let mut d = data.lock().unwrap();
 
 	if d.contains_key(&args[1].clone().trim().to_string()) {
 		d.remove(&args[1].clone().trim().to_string());
@@ -193,56 +256,43 @@ fn set_variable(args: Vec<String>, _: String, rv: Option<Receiver<i16>>) -> Resu
 	return Ok(());
 }
 
-fn list_variables(_: Vec<String>, _: String, rv: Option<Receiver<i16>>) -> Result<(), String> {
+fn list_variables(_: Vec<String>, _: String, rv: Option<Receiver<i16>>, io: &mut Io) -> Result<(), String> {
 	
 	if rv.is_none() {
 		let d = data.lock().unwrap();
 		for (key, val) in d.iter() {
-			println!("{} = \"{}\"", key, val);
+			writeln!(io.stdout, "{} = \"{}\"", key, val).map_err(|e| e.to_string())?;
 		}
 	} else {
-		let d;
+		let d = data.lock().unwrap();
 		let channel = rv.unwrap();
 
-		loop {
-			if let Ok(o) = channel.try_recv() {
-				if o == 1 {
-					return Err("Could not aquire variable mutex".to_string());
-				}
-			}
-			if let Ok(o) = data.try_lock() {
-				d = o;
-				break;
-			}
-		}
-
 		for (key, val) in d.iter() {
 			if let Ok(o) = channel.try_recv() {
 				if o == 1 {
 					break;
 				}
 			}
-			println!("{} = \"{}\"", key, val);
+			writeln!(io.stdout, "{} = \"{}\"", key, val).map_err(|e| e.to_string())?;
 		}
 	}
 
 	return Ok(());
 }
 
-fn list_directory(args: Vec<String>, _: String, rv: Option<Receiver<i16>>) -> Result<(), String> {
+fn list_directory(args: Vec<String>, _: String, rv: Option<Receiver<i16>>, io: &mut Io) -> Result<(), String> {
 
 	if args.len() > 2 {
-		println!("Syntax: ls {{directory}}");
+		writeln!(io.stdout, "Syntax: ls {{directory}}").map_err(|e| e.to_string())?;
 		return Ok(());
 	}
 	let files = if args.len() == 2 {
 		if !(Path::new(&args[1]).is_dir()) {
 			if args[1] == "help" {
-				println!("Syntax: ls {{directory}}");
+				writeln!(io.stdout, "Syntax: ls {{directory}}").map_err(|e| e.to_string())?;
 				return Ok(());
 			}
-			println!("Directory does not exist");
-			return Ok(());
+			return Err("Directory does not exist".to_string());
 		}
 		match std::fs::read_dir(&args[1]) {
 			Ok(o) => {
@@ -282,14 +332,14 @@ fn list_directory(args: Vec<String>, _: String, rv: Option<Receiver<i16>>) -> Re
 			if f.metadata().unwrap().is_file() {
 				let size = f.metadata().unwrap().len().to_string();
 				if crate::is_executable(f.path().canonicalize().unwrap().to_str().unwrap()) {
-					print!("{} {}{} {}\n", t.format("%b %d %H:%M"), " ".repeat(longest_file_size-size.len()), size, console::style(f.file_name().to_str().unwrap()).green().bright());
+					writeln!(io.stdout, "{} {}{} {}", t.format("%b %d %H:%M"), " ".repeat(longest_file_size-size.len()), size, console::style(f.file_name().to_str().unwrap()).green().bright()).map_err(|e| e.to_string())?;
 				} else {
-					print!("{} {}{} {}\n", t.format("%b %d %H:%M"), " ".repeat(longest_file_size-size.len()), size, f.file_name().to_str().unwrap());
+					writeln!(io.stdout, "{} {}{} {}", t.format("%b %d %H:%M"), " ".repeat(longest_file_size-size.len()), size, f.file_name().to_str().unwrap()).map_err(|e| e.to_string())?;
 				}
 				
 				
 			} else {
-				print!("{} {}<DIR> {}\n", t.format("%b %d %H:%M"), " ".repeat(longest_file_size-5), console::style(f.file_name().to_str().unwrap()).blue().bright());
+				writeln!(io.stdout, "{} {}<DIR> {}", t.format("%b %d %H:%M"), " ".repeat(longest_file_size-5), console::style(f.file_name().to_str().unwrap()).blue().bright()).map_err(|e| e.to_string())?;
 			}
 		}
 	} else {
@@ -298,14 +348,14 @@ fn list_directory(args: Vec<String>, _: String, rv: Option<Receiver<i16>>) -> Re
 			if f.metadata().unwrap().is_file() {
 				let size = f.metadata().unwrap().len().to_string();
 				if crate::is_executable(f.path().canonicalize().unwrap().to_str().unwrap()) {
-					print!("{} {}{} {}\n", t.format("%b %d %H:%M"), " ".repeat(longest_file_size-size.len()), size, console::style(f.file_name().to_str().unwrap()).green().bright());
+					writeln!(io.stdout, "{} {}{} {}", t.format("%b %d %H:%M"), " ".repeat(longest_file_size-size.len()), size, console::style(f.file_name().to_str().unwrap()).green().bright()).map_err(|e| e.to_string())?;
 				} else {
-					print!("{} {}{} {}\n", t.format("%b %d %H:%M"), " ".repeat(longest_file_size-size.len()), size, f.file_name().to_str().unwrap());
+					writeln!(io.stdout, "{} {}{} {}", t.format("%b %d %H:%M"), " ".repeat(longest_file_size-size.len()), size, f.file_name().to_str().unwrap()).map_err(|e| e.to_string())?;
 				}
 				
 				
 			} else {
-				print!("{} {}<DIR> {}\n", t.format("%b %d %H:%M"), " ".repeat(longest_file_size-5), console::style(f.file_name().to_str().unwrap()).blue().bright());
+				writeln!(io.stdout, "{} {}<DIR> {}", t.format("%b %d %H:%M"), " ".repeat(longest_file_size-5), console::style(f.file_name().to_str().unwrap()).blue().bright()).map_err(|e| e.to_string())?;
 			}
 		}
 	}
@@ -314,47 +364,46 @@ fn list_directory(args: Vec<String>, _: String, rv: Option<Receiver<i16>>) -> Re
 	return Ok(());
 }
 
-fn update_path(_: Vec<String>, _: String, _: Option<Receiver<i16>>) -> Result<(), String> {
+fn update_path(_: Vec<String>, _: String, _: Option<Receiver<i16>>, io: &mut Io) -> Result<(), String> {
 
-	println!("Updating path");
+	writeln!(io.stdout, "Updating path").map_err(|e| e.to_string())?;
 	crate::update_path();
-	println!("Path updated");
+	writeln!(io.stdout, "Path updated").map_err(|e| e.to_string())?;
 
 	return Ok(());
 
 }
 
-fn find_path(args: Vec<String>, _: String, _: Option<Receiver<i16>>) -> Result<(), String> {
+fn find_path(args: Vec<String>, _: String, _: Option<Receiver<i16>>, io: &mut Io) -> Result<(), String> {
 
 	if args.len() < 2 {
-		println!("Syntax: which {{pattern}}");
+		writeln!(io.stdout, "Syntax: which {{pattern}}").map_err(|e| e.to_string())?;
 		return Ok(());
 	}
 
 	if let Some(s) = crate::is_valid_exe_in_current_path(args[1].clone()) {
-		println!("{}", s);
+		writeln!(io.stdout, "{}", s).map_err(|e| e.to_string())?;
 	} else if let Some(s) = crate::is_valid_exe_in_path(args[1].clone()) {
-		println!("{}", s);
+		writeln!(io.stdout, "{}", s).map_err(|e| e.to_string())?;
 	} else {
-		println!("Could not find executable");
+		return Err("Could not find executable".to_string());
 	}
 	
 	return Ok(());
 
 }
 
-fn make_dir(args: Vec<String>, _: String, _: Option<Receiver<i16>>) -> Result<(), String> {
+fn make_dir(args: Vec<String>, _: String, _: Option<Receiver<i16>>, io: &mut Io) -> Result<(), String> {
 
 	if args.len() == 1 {
-		println!("Syntax: mkdir {{directory}}");
+		writeln!(io.stdout, "Syntax: mkdir {{directory}}").map_err(|e| e.to_string())?;
 		return Ok(());
 	}
 	
 	for i in &args[1..] {
 		let p = std::path::Path::new(i);
 		if p.exists() {
-			println!("Path already exists");
-			return Ok(());
+			return Err("Path already exists".to_string());
 		}
 		if let Err(e) = std::fs::create_dir_all(p) {
 			return Err(e.to_string());
@@ -364,18 +413,17 @@ fn make_dir(args: Vec<String>, _: String, _: Option<Receiver<i16>>) -> Result<()
 	return Ok(());
 }
 
-fn remove(args: Vec<String>, _: String, rv: Option<Receiver<i16>>) -> Result<(), String> {
+fn remove(args: Vec<String>, _: String, rv: Option<Receiver<i16>>, io: &mut Io) -> Result<(), String> {
 
 	if args.len() == 1 {
-		println!("Syntax: rm {{directory/file}}");
+		writeln!(io.stdout, "Syntax: rm {{directory/file}}").map_err(|e| e.to_string())?;
 		return Ok(());
 	}
 
 	for i in &args[1..] {
 		let p = std::path::Path::new(i);
 		if !p.exists() {
-			println!("Path does not exist");
-			return Ok(());
+			return Err("Path does not exist".to_string());
 		}
 		match p.is_dir() {
 			true => {
@@ -444,7 +492,7 @@ fn remove(args: Vec<String>, _: String, rv: Option<Receiver<i16>>) -> Result<(),
 				if let Err(e) = std::fs::remove_file(p) {
 					return Err(e.to_string());
 				}
-				println!("Deleted {}", i);
+				writeln!(io.stdout, "Deleted {}", i).map_err(|e| e.to_string())?;
 			}
 		}
 	}
@@ -452,17 +500,16 @@ fn remove(args: Vec<String>, _: String, rv: Option<Receiver<i16>>) -> Result<(),
 	return Ok(());
 }
 
-fn copy(args: Vec<String>, _: String, rv: Option<Receiver<i16>>) -> Result<(), String> {
+fn copy(args: Vec<String>, _: String, rv: Option<Receiver<i16>>, io: &mut Io) -> Result<(), String> {
 
 	if args.len() != 3 {
-		println!("Syntax: copy {{source directory/file}} {{destination directory/file}}");
+		writeln!(io.stdout, "Syntax: copy {{source directory/file}} {{destination directory/file}}").map_err(|e| e.to_string())?;
 		return Ok(());
 	}
 
 	let p = std::path::Path::new(&args[1]);
 	if !p.exists() {
-		println!("Path does not exist");
-		return Ok(());
+		return Err("Path does not exist".to_string());
 	}
 	match p.is_dir() {
 		true => {
@@ -623,4 +670,224 @@ fn copy(args: Vec<String>, _: String, rv: Option<Receiver<i16>>) -> Result<(), S
 	}
 
 	return Ok(());
-}
\ No newline at end of file
+}
+
+fn job_from_args(args: &[String]) -> Result<usize, String> {
+	if args.len() > 1 {
+		return crate::jobs::parse_spec(&args[1]);
+	}
+	return crate::jobs::current().ok_or("No current job".to_string());
+}
+
+fn list_jobs(_: Vec<String>, _: String, _: Option<Receiver<i16>>, io: &mut Io) -> Result<(), String> {
+
+	let mut t = crate::jobs::table.lock().unwrap();
+	let current = t.last().map(|x| x.id);
+	for job in t.iter_mut() {
+		job.poll()?;
+	}
+	for job in t.iter() {
+		writeln!(io.stdout, "{}", crate::jobs::format_job(job, Some(job.id) == current)).map_err(|e| e.to_string())?;
+	}
+	t.retain(|x| !matches!(x.state(), crate::jobs::JobState::Done(_)));
+
+	return Ok(());
+}
+
+fn foreground(args: Vec<String>, _: String, _: Option<Receiver<i16>>, io: &mut Io) -> Result<(), String> {
+
+	if args.len() > 2 {
+		writeln!(io.stdout, "Syntax: fg {{%job}}").map_err(|e| e.to_string())?;
+		return Ok(());
+	}
+	let id = job_from_args(&args)?;
+	let mut job = match crate::jobs::take(id) {
+		Some(o) => {
+			o
+		},
+		None => {
+			return Err(format!("No such job: {}", id));
+		}
+	};
+	writeln!(io.stdout, "{}", job.command).map_err(|e| e.to_string())?;
+	io.stdout.flush().map_err(|e| e.to_string())?;
+
+	crate::jobs::give_terminal(&job);
+	if let Err(e) = crate::jobs::resume(&mut job) {
+		crate::jobs::add(job);
+		return Err(e);
+	}
+	io.exit_code = Some(crate::jobs::wait_foreground(job)?);
+
+	return Ok(());
+}
+
+fn background(args: Vec<String>, _: String, _: Option<Receiver<i16>>, io: &mut Io) -> Result<(), String> {
+
+	if args.len() > 2 {
+		writeln!(io.stdout, "Syntax: bg {{%job}}").map_err(|e| e.to_string())?;
+		return Ok(());
+	}
+	let id = job_from_args(&args)?;
+	let mut job = match crate::jobs::take(id) {
+		Some(o) => {
+			o
+		},
+		None => {
+			return Err(format!("No such job: {}", id));
+		}
+	};
+	let result = crate::jobs::resume(&mut job);
+	writeln!(io.stdout, "{}", crate::jobs::format_job(&job, true)).map_err(|e| e.to_string())?;
+	crate::jobs::add(job);
+
+	return result;
+}
+
+fn kill(args: Vec<String>, _: String, _: Option<Receiver<i16>>, io: &mut Io) -> Result<(), String> {
+
+	let (sig, targets) = match args.get(1) {
+		Some(s) if s.starts_with('-') && args.len() > 2 => (crate::jobs::parse_signal(&s[1..])?, &args[2..]),
+		_ => (crate::jobs::parse_signal("TERM")?, &args[1..]),
+	};
+	if targets.is_empty() {
+		writeln!(io.stdout, "Syntax: kill {{-signal}} {{%job/pid}}").map_err(|e| e.to_string())?;
+		return Ok(());
+	}
+
+	for target in targets {
+		if target.starts_with('%') {
+			let id = crate::jobs::parse_spec(target)?;
+			let mut t = crate::jobs::table.lock().unwrap();
+			if let Some(job) = t.iter_mut().find(|x| x.id == id) {
+				crate::jobs::signal_job(job, sig)?;
+			}
+		} else {
+			match target.parse::<u32>() {
+				Ok(o) => crate::jobs::signal_pid(o, sig)?,
+				Err(_) => return Err(format!("{} is not a valid job or process id", target)),
+			}
+		}
+	}
+
+	return Ok(());
+}
+
+fn history(args: Vec<String>, _: String, rv: Option<Receiver<i16>>, io: &mut Io) -> Result<(), String> {
+
+	match args.get(1).map(|x| x.as_str()) {
+		Some("-c") => {
+			return crate::history::clear();
+		},
+		Some("-d") => {
+			return match args.get(2).map(|x| x.parse::<usize>()) {
+				Some(Ok(o)) => crate::history::delete(o),
+				_ => {
+					writeln!(io.stdout, "Syntax: history -d {{number}}").map_err(|e| e.to_string())?;
+					Ok(())
+				}
+			};
+		},
+		_ => (),
+	}
+
+	let filter = args[1..].join(" ");
+	let entries = crate::history::entries.lock().unwrap().clone();
+	for (i, e) in entries.iter().enumerate() {
+		if let Some(channel) = &rv {
+			if let Ok(1) = channel.try_recv() {
+				break;
+			}
+		}
+		if !e.line.contains(&filter) {
+			continue;
+		}
+		let time = match chrono::DateTime::from_timestamp(e.time, 0) {
+			Some(t) if e.time != 0 => t.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S").to_string(),
+			_ => " ".repeat(19),
+		};
+		writeln!(io.stdout, "{:>5}  {}  {}", i + 1, time, e.line).map_err(|e| e.to_string())?;
+	}
+
+	return Ok(());
+}
+
+fn complete(args: Vec<String>, _: String, _: Option<Receiver<i16>>, io: &mut Io) -> Result<(), String> {
+
+	if args.len() == 1 {
+		for line in crate::completion::describe() {
+			writeln!(io.stdout, "{}", line).map_err(|e| e.to_string())?;
+		}
+		return Ok(());
+	}
+	if crate::completion::register(&args[1..])? == 0 {
+		writeln!(io.stdout, "Syntax: complete {{-W words}} {{-f}} {{-d}} {{-C program}} {{-r}} {{command}}").map_err(|e| e.to_string())?;
+	}
+
+	return Ok(());
+}
+
+fn source(args: Vec<String>, _: String, _: Option<Receiver<i16>>, io: &mut Io) -> Result<(), String> {
+	let file = match args.get(1) {
+		Some(o) => o.clone(),
+		None => match crate::script::rc_path() {
+			Some(o) => o.to_string_lossy().to_string(),
+			None => {
+				writeln!(io.stdout, "Syntax: source {{file}}").map_err(|e| e.to_string())?;
+				return Ok(());
+			}
+		},
+	};
+	io.exit_code = Some(crate::script::run_file(&file, &create_commands())?);
+	return Ok(());
+}
+
+// An alias the way it is defined, quoted so it can be pasted back
+fn describe_alias(name: &str, value: &str) -> String {
+	return format!("alias {}={}", name, crate::parser::single_quote(value));
+}
+
+fn alias(args: Vec<String>, _: String, _: Option<Receiver<i16>>, io: &mut Io) -> Result<(), String> {
+	if args.len() == 1 {
+		let a = aliases.lock().unwrap();
+		let mut names: Vec<&String> = a.keys().collect();
+		names.sort();
+		for name in names {
+			writeln!(io.stdout, "{}", describe_alias(name, &a[name])).map_err(|e| e.to_string())?;
+		}
+		return Ok(());
+	}
+
+	for arg in args[1..].iter() {
+		match arg.split_once('=') {
+			Some((name, value)) => {
+				if name.is_empty() || name.chars().any(|x| x.is_whitespace() || "|;&<>()'\"\\$/".contains(x)) {
+					return Err(format!("{}: invalid alias name", name));
+				}
+				aliases.lock().unwrap().insert(name.to_string(), value.to_string());
+			},
+			None => match aliases.lock().unwrap().get(arg) {
+				Some(o) => writeln!(io.stdout, "{}", describe_alias(arg, o)).map_err(|e| e.to_string())?,
+				None => return Err(format!("{}: not found", arg)),
+			},
+		}
+	}
+	return Ok(());
+}
+
+fn unalias(args: Vec<String>, _: String, _: Option<Receiver<i16>>, io: &mut Io) -> Result<(), String> {
+	if args.len() == 1 {
+		writeln!(io.stdout, "Syntax: unalias {{-a}} {{name}}").map_err(|e| e.to_string())?;
+		return Ok(());
+	}
+
+	let mut a = aliases.lock().unwrap();
+	for arg in args[1..].iter() {
+		if arg == "-a" {
+			a.clear();
+		} else if a.remove(arg).is_none() {
+			return Err(format!("{}: not found", arg));
+		}
+	}
+	return Ok(());
+}
@@ -0,0 +1,427 @@
+#![allow(non_upper_case_globals)]
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Mutex;
+use lazy_static::lazy_static;
+
+use crate::commands::Command;
+
+lazy_static! {
+	// Completion rules by command, or by command and subcommand like "git commit"
+	pub static ref specs: Mutex<HashMap<String, Spec>> = Mutex::new(HashMap::new());
+}
+
+// What the arguments of a command complete to, set with the `complete` builtin
+#[derive(Clone, Default)]
+pub struct Spec {
+	// -W: fixed words such as subcommands and flags
+	pub words: Vec<String>,
+	// -f: files and directories
+	pub files: bool,
+	// -d: directories only
+	pub directories: bool,
+	// -C: a program printing one candidate per line
+	pub command: Option<String>,
+}
+
+pub struct Completion {
+	// Byte offset in the line where the completed text starts
+	pub start: usize,
+	pub candidates: Vec<String>,
+	// Whether the candidates name variables, which are closed with '$' instead of a space
+	pub variable: bool,
+}
+
+// Splits the text before the cursor into the start of the word being completed
+// and the words before it in the same pipeline stage
+fn current_word(line: &str) -> (usize, Vec<String>) {
+	let mut start: usize = 0;
+	let mut stage: usize = 0;
+	let mut chars = line.char_indices();
+	while let Some((i, c)) = chars.next() {
+		if c == '\\' {
+			chars.next();
+		} else if c.is_whitespace() || crate::parser::is_operator(c) {
+			start = i + c.len_utf8();
+			if (crate::parser::is_operator(c) && c != '<' && c != '>') || c == '\n' {
+				stage = start;
+			}
+		}
+	}
+	let words = match crate::parser::words(&line[stage..start]) {
+		Ok(o) => o.into_iter().map(|x| x.text).collect(),
+		Err(_) => line[stage..start].split_whitespace().map(|x| x.to_string()).collect(),
+	};
+	return (start, words);
+}
+
+fn unescape(word: &str) -> String {
+	let mut result = String::new();
+	let mut chars = word.chars();
+	while let Some(c) = chars.next() {
+		if c == '\\' {
+			if let Some(n) = chars.next() {
+				result.push(n);
+			}
+		} else {
+			result.push(c);
+		}
+	}
+	return result;
+}
+
+fn escape(word: &str) -> String {
+	let mut result = String::new();
+	for c in word.chars() {
+		if c.is_whitespace() || crate::parser::is_operator(c) || "\\'\"$".contains(c) {
+			result.push('\\');
+		}
+		result.push(c);
+	}
+	return result;
+}
+
+// Files and directories starting with `word`; directories end with '/'
+fn paths(word: &str, only_directories: bool) -> Vec<String> {
+	let (dir, prefix) = match word.rfind('/') {
+		Some(i) => (&word[..i + 1], &word[i + 1..]),
+		None => ("", word),
+	};
+	let entries = match std::fs::read_dir(if dir.is_empty() { "." } else { dir }) {
+		Ok(o) => o,
+		Err(_) => return Vec::new(),
+	};
+	let mut result: Vec<String> = Vec::new();
+	for entry in entries.flatten() {
+		let name = entry.file_name().to_string_lossy().to_string();
+		// Hidden files are only offered once a '.' was typed
+		if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+			continue;
+		}
+		let is_dir = entry.path().is_dir();
+		if only_directories && !is_dir {
+			continue;
+		}
+		result.push(format!("{}{}{}", dir, name, if is_dir { "/" } else { "" }));
+	}
+	return result;
+}
+
+fn executables(prefix: &str) -> Vec<String> {
+	let mut result: Vec<String> = Vec::new();
+	for dir in crate::commands::path.lock().unwrap().iter() {
+		let entries = match std::fs::read_dir(dir) {
+			Ok(o) => o,
+			Err(_) => continue,
+		};
+		for entry in entries.flatten() {
+			let name = entry.file_name().to_string_lossy().to_string();
+			let path = entry.path();
+			if name.starts_with(prefix) && path.is_file() && crate::is_executable(path.to_string_lossy()) {
+				result.push(name);
+			}
+		}
+	}
+	return result;
+}
+
+fn variables(prefix: &str) -> Vec<String> {
+	let mut result: Vec<String> = crate::commands::data.lock().unwrap().keys().filter(|x| x.starts_with(prefix)).cloned().collect();
+	if "PATH".starts_with(prefix) {
+		result.push("PATH".to_string());
+	}
+	return result;
+}
+
+// Works out what the word before the cursor is (a command, a path or a `$name$`
+// variable) and returns everything it could be completed to
+pub fn complete(line: &str, cursor: usize, cmds: &[Command<'static>]) -> Completion {
+	let line = &line[..cursor];
+	let (start, words) = current_word(line);
+	let word = &line[start..];
+
+	// An odd number of '$' means the cursor is inside a variable name
+	if word.matches('$').count() % 2 == 1 {
+		let name_start = start + word.rfind('$').unwrap() + 1;
+		let mut candidates = variables(&line[name_start..]);
+		candidates.sort();
+		candidates.dedup();
+		return Completion { start: name_start, candidates, variable: true };
+	}
+
+	let text = unescape(word);
+	let mut candidates: Vec<String> = if words.is_empty() {
+		if text.contains('/') {
+			paths(&text, false)
+		} else {
+			let mut c: Vec<String> = cmds.iter().filter(|x| x.name.starts_with(&text)).map(|x| x.name.clone()).collect();
+			c.extend(crate::commands::aliases.lock().unwrap().keys().filter(|x| x.starts_with(&text)).cloned());
+			c.extend(crate::functions::defined.lock().unwrap().keys().filter(|x| x.starts_with(&text)).cloned());
+			c.extend(executables(&text));
+			c
+		}
+	} else {
+		match find_spec(&words) {
+			Some(spec) => from_spec(&spec, &words, &text, line),
+			None if words[0] == "cd" => paths(&text, true),
+			None => paths(&text, false),
+		}
+	};
+	candidates.sort();
+	candidates.dedup();
+	return Completion {
+		start,
+		candidates: candidates.iter().map(|x| escape(x)).collect(),
+		variable: false,
+	};
+}
+
+// The longest text every candidate starts with
+pub fn common_prefix(candidates: &[String]) -> String {
+	let mut prefix = match candidates.first() {
+		Some(o) => o.clone(),
+		None => return String::new(),
+	};
+	for c in candidates[1..].iter() {
+		let len = prefix.chars().zip(c.chars()).take_while(|(a, b)| a == b).map(|(a, _)| a.len_utf8()).sum();
+		prefix.truncate(len);
+	}
+	return prefix;
+}
+
+// The part of a candidate shown in the menu, without its directory
+pub fn display_name(candidate: &str) -> String {
+	let trimmed = candidate.trim_end_matches('/');
+	let name = match Path::new(trimmed).file_name() {
+		Some(o) => o.to_string_lossy().to_string(),
+		None => trimmed.to_string(),
+	};
+	if candidate.ends_with('/') {
+		return format!("{}/", name);
+	}
+	return name;
+}
+
+// The rule for the longest run of leading words that has one, so "git commit"
+// is preferred over "git"
+fn find_spec(words: &[String]) -> Option<Spec> {
+	let s = specs.lock().unwrap();
+	for i in (1..=words.len()).rev() {
+		if let Some(spec) = s.get(&words[..i].join(" ")) {
+			return Some(spec.clone());
+		}
+	}
+	return None;
+}
+
+fn from_spec(spec: &Spec, words: &[String], text: &str, line: &str) -> Vec<String> {
+	let mut result: Vec<String> = spec.words.iter().filter(|x| x.starts_with(text)).cloned().collect();
+	if spec.files || spec.directories {
+		result.extend(paths(text, !spec.files));
+	}
+	if let Some(command) = &spec.command {
+		match run_external(command, words, text, line) {
+			Ok(o) => result.extend(o),
+			Err(e) => crate::debug(format!("complete -C {}: {}", command, e)),
+		}
+	}
+	return result;
+}
+
+// Runs a -C program the way bash does: it gets the command name, the word being
+// completed and the word before it as arguments, with the line up to the cursor
+// in COMP_LINE and the cursor position in COMP_POINT
+fn run_external(command: &str, words: &[String], text: &str, line: &str) -> Result<Vec<String>, String> {
+	let mut args: Vec<String> = crate::parser::words(command)?.into_iter().map(|x| x.text).collect();
+	if args.is_empty() {
+		return Err("No program given".to_string());
+	}
+	args.push(words[0].clone());
+	args.push(text.to_string());
+	args.push(words.last().unwrap().clone());
+	let output = std::process::Command::new(&args[0])
+		.args(&args[1..])
+		.env("COMP_LINE", line)
+		.env("COMP_POINT", line.len().to_string())
+		.stdin(Stdio::null())
+		.stderr(Stdio::null())
+		.output()
+		.map_err(|e| e.to_string())?;
+	return Ok(String::from_utf8_lossy(&output.stdout).lines().filter(|x| !x.is_empty()).map(|x| x.to_string()).collect());
+}
+
+// Reads `complete` style options (-W words, -f, -d, -C program, -r) followed
+// by the commands they apply to
+pub fn register(args: &[String]) -> Result<usize, String> {
+	let mut spec = Spec::default();
+	let mut remove = false;
+	let mut names: Vec<String> = Vec::new();
+	let mut args = args.iter();
+	while let Some(arg) = args.next() {
+		match arg.as_str() {
+			"-W" => match args.next() {
+				Some(o) => spec.words.extend(o.split_whitespace().map(|x| x.to_string())),
+				None => return Err("-W needs a list of words".to_string()),
+			},
+			"-C" => match args.next() {
+				Some(o) => spec.command = Some(o.clone()),
+				None => return Err("-C needs a program".to_string()),
+			},
+			"-f" => spec.files = true,
+			"-d" => spec.directories = true,
+			"-r" => remove = true,
+			o if o.starts_with('-') => return Err(format!("Unknown option {}", o)),
+			o => names.push(o.split_whitespace().collect::<Vec<&str>>().join(" ")),
+		}
+	}
+	let mut s = specs.lock().unwrap();
+	for name in names.iter() {
+		if remove {
+			s.remove(name);
+		} else {
+			s.insert(name.clone(), spec.clone());
+		}
+	}
+	return Ok(names.len());
+}
+
+fn quote(s: &str) -> String {
+	if s.is_empty() || s.chars().any(|x| x.is_whitespace() || "'\"\\$|;&<>#".contains(x)) {
+		return crate::parser::single_quote(s);
+	}
+	return s.to_string();
+}
+
+// Every rule as the `complete` command that creates it
+pub fn describe() -> Vec<String> {
+	let s = specs.lock().unwrap();
+	let mut result: Vec<String> = Vec::new();
+	for (name, spec) in s.iter() {
+		let mut line = "complete".to_string();
+		if !spec.words.is_empty() {
+			line.push_str(&format!(" -W {}", quote(&spec.words.join(" "))));
+		}
+		if spec.files {
+			line.push_str(" -f");
+		}
+		if spec.directories {
+			line.push_str(" -d");
+		}
+		if let Some(c) = &spec.command {
+			line.push_str(&format!(" -C {}", quote(c)));
+		}
+		line.push_str(&format!(" {}", quote(name)));
+		result.push(line);
+	}
+	result.sort();
+	return result;
+}
+
+// Rules can also be kept in a file, one `complete` command per line,
+// e.g. ~/.config/terminal/completions on Linux
+pub fn config_path() -> Option<PathBuf> {
+	return dirs::config_dir().map(|x| x.join("terminal").join("completions"));
+}
+
+pub fn load() {
+	let path = match config_path() {
+		Some(o) => o,
+		None => return,
+	};
+	let content = match std::fs::read_to_string(&path) {
+		Ok(o) => o,
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+		Err(e) => {
+			crate::print_error(line!(), format!("Cannot read {}: {}", path.display(), e));
+			return;
+		}
+	};
+	for (i, line) in content.lines().enumerate() {
+		let line = line.trim();
+		if line.is_empty() || line.starts_with('#') {
+			continue;
+		}
+		let args: Vec<String> = match crate::parser::words(line) {
+			Ok(o) => o.into_iter().map(|x| x.text).collect(),
+			Err(e) => {
+				crate::print_error(line!(), format!("{}:{}: {}", path.display(), i + 1, e));
+				continue;
+			}
+		};
+		if args.first().map(|x| x.as_str()) != Some("complete") {
+			crate::print_error(line!(), format!("{}:{}: Expected a complete command", path.display(), i + 1));
+			continue;
+		}
+		if let Err(e) = register(&args[1..]) {
+			crate::print_error(line!(), format!("{}:{}: {}", path.display(), i + 1, e));
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn words_before_cursor() {
+		assert_eq!(current_word("ls -l fo"), (6, vec!["ls".to_string(), "-l".to_string()]));
+		assert_eq!(current_word("echo a | gr"), (9, vec![]));
+		assert_eq!(current_word("cat < fi"), (6, vec!["cat".to_string()]));
+		assert_eq!(current_word("ls my\\ fi"), (3, vec!["ls".to_string()]));
+		assert_eq!(current_word(""), (0, vec![]));
+	}
+
+	#[test]
+	fn escaping() {
+		for word in ["a b", "it's", "$x$", "a|b;c", "back\\slash"] {
+			assert_eq!(unescape(&escape(word)), word);
+		}
+		assert_eq!(escape("a b"), "a\\ b");
+	}
+
+	#[test]
+	fn prefixes() {
+		let candidates = ["src/main.rs", "src/mod.rs"].map(String::from);
+		assert_eq!(common_prefix(&candidates), "src/m");
+		assert_eq!(common_prefix(&["école".to_string(), "écrit".to_string()]), "éc");
+		assert_eq!(common_prefix(&[]), "");
+		assert_eq!(display_name("src/main.rs"), "main.rs");
+		assert_eq!(display_name("src/bin/"), "bin/");
+	}
+
+	#[test]
+	fn complete_paths_and_variables() {
+		let dir = std::env::temp_dir().join(format!("terminal-complete-{}", std::process::id()));
+		std::fs::create_dir_all(dir.join("sub dir")).unwrap();
+		std::fs::write(dir.join("file"), "").unwrap();
+		let prefix = format!("{}/", dir.to_string_lossy());
+		let mut found = paths(&prefix, false);
+		found.sort();
+		assert_eq!(found, [format!("{}file", prefix), format!("{}sub dir/", prefix)]);
+		assert_eq!(paths(&prefix, true), [format!("{}sub dir/", prefix)]);
+		std::fs::remove_dir_all(&dir).unwrap();
+
+		let completion = complete("echo $PA", 8, &[]);
+		assert!(completion.variable);
+		assert_eq!(completion.start, 6);
+		assert!(completion.candidates.contains(&"PATH".to_string()));
+	}
+
+	#[test]
+	fn specs_by_command() {
+		let args = ["-W", "commit push", "-f", "tgit", "tgit commit"].map(String::from);
+		assert_eq!(register(&args), Ok(2));
+		let words = ["tgit", "commit"].map(String::from);
+		assert!(find_spec(&words).is_some_and(|x| x.files && x.words == ["commit", "push"]));
+		assert_eq!(from_spec(&find_spec(&words[..1]).unwrap(), &words[..1], "co", "tgit co"), ["commit"]);
+		assert!(describe().contains(&"complete -W 'commit push' -f 'tgit commit'".to_string()));
+		assert_eq!(register(&["-r", "tgit commit"].map(String::from)), Ok(1));
+		assert!(find_spec(&words).is_some_and(|x| x.words == ["commit", "push"]));
+		assert!(register(&["-W".to_string()]).is_err());
+		assert!(register(&["-x".to_string()]).is_err());
+		assert_eq!(register(&["-r", "tgit"].map(String::from)), Ok(1));
+		assert!(find_spec(&words).is_none());
+	}
+}
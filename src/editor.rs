@@ -0,0 +1,262 @@
+#![allow(non_upper_case_globals)]
+
+use std::sync::Mutex;
+use lazy_static::lazy_static;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+lazy_static! {
+	// Text removed by the kill commands, most recent last, shared by every line read
+	static ref kill_ring: Mutex<Vec<String>> = Mutex::new(Vec::new());
+}
+
+// Held by the tests that use the kill ring, since tests run in parallel
+#[cfg(test)]
+pub static kill_ring_test: Mutex<()> = Mutex::new(());
+
+const KILL_RING_SIZE: usize = 10;
+
+// The cursor moves over grapheme clusters, so an accented letter typed as two
+// code points or an emoji made of several is one step, like in a terminal
+pub fn previous_grapheme(inp: &str, pos: usize) -> usize {
+	return inp[..pos].grapheme_indices(true).next_back().map(|x| x.0).unwrap_or(0);
+}
+
+pub fn next_grapheme(inp: &str, pos: usize) -> usize {
+	return inp[pos..].graphemes(true).next().map(|x| pos + x.len()).unwrap_or(inp.len());
+}
+
+// How many columns the terminal uses to show `s`, which must not contain escape codes
+pub fn width(s: &str) -> usize {
+	return s.graphemes(true).map(|x| x.width()).sum();
+}
+
+fn is_word(g: &str) -> bool {
+	return g.chars().next().is_some_and(|c| c.is_alphanumeric() || c == '_');
+}
+
+// Where the word before `pos` starts, like readline's Alt-B
+pub fn word_start(inp: &str, pos: usize) -> usize {
+	let mut start = pos;
+	let mut in_word = false;
+	for (i, g) in inp[..pos].grapheme_indices(true).rev() {
+		if is_word(g) {
+			in_word = true;
+		} else if in_word {
+			break;
+		}
+		start = i;
+	}
+	return start;
+}
+
+// Where the word after `pos` ends, like readline's Alt-F
+pub fn word_end(inp: &str, pos: usize) -> usize {
+	let mut in_word = false;
+	for (i, g) in inp[pos..].grapheme_indices(true) {
+		if is_word(g) {
+			in_word = true;
+		} else if in_word {
+			return pos + i;
+		}
+	}
+	return inp.len();
+}
+
+// Where the whitespace separated word before `pos` starts, for Ctrl-W
+pub fn big_word_start(inp: &str, pos: usize) -> usize {
+	let trimmed = inp[..pos].trim_end();
+	return match trimmed.rfind(char::is_whitespace) {
+		Some(i) => i + trimmed[i..].chars().next().unwrap().len_utf8(),
+		None => 0,
+	};
+}
+
+// Removes `start..end` from the line and saves it in the kill ring. Consecutive
+// kills (`append`) are joined into one entry, in the order they appear in the
+// line, so `backward` kills go in front of the earlier text
+pub fn kill(inp: &mut String, start: usize, end: usize, append: bool, backward: bool) {
+	if start >= end {
+		return;
+	}
+	let text: String = inp.drain(start..end).collect();
+	let mut ring = kill_ring.lock().unwrap();
+	match ring.last_mut() {
+		Some(last) if append && backward => last.insert_str(0, &text),
+		Some(last) if append => last.push_str(&text),
+		_ => push(&mut ring, text),
+	}
+}
+
+fn push(ring: &mut Vec<String>, text: String) {
+	ring.push(text);
+	if ring.len() > KILL_RING_SIZE {
+		ring.remove(0);
+	}
+}
+
+// Puts text in the kill ring without removing it from the line, for vi's y
+pub fn save(text: &str) {
+	push(&mut kill_ring.lock().unwrap(), text.to_string());
+}
+
+// The most recently killed text, for Ctrl-Y
+pub fn yank() -> Option<String> {
+	return kill_ring.lock().unwrap().last().cloned();
+}
+
+// Rotates the kill ring and returns the entry before the one just yanked, for Alt-Y
+pub fn yank_pop() -> Option<String> {
+	let mut ring = kill_ring.lock().unwrap();
+	if ring.is_empty() {
+		return None;
+	}
+	ring.rotate_right(1);
+	return ring.last().cloned();
+}
+
+// The line and cursor position from before each change, most recent last, and
+// the ones undone since the last change for redo
+pub struct Undo {
+	done: Vec<(String, usize)>,
+	undone: Vec<(String, usize)>,
+}
+
+impl Undo {
+	pub fn new() -> Undo {
+		return Undo { done: Vec::new(), undone: Vec::new() };
+	}
+
+	// Remembers the line as it was before a change
+	pub fn save(&mut self, inp: &str, cursor: usize) {
+		self.done.push((inp.to_string(), cursor));
+		self.undone.clear();
+	}
+
+	// Goes back to the line from before the last change
+	pub fn undo(&mut self, inp: &mut String, cursor: &mut usize) {
+		step(&mut self.done, &mut self.undone, inp, cursor);
+	}
+
+	pub fn redo(&mut self, inp: &mut String, cursor: &mut usize) {
+		step(&mut self.undone, &mut self.done, inp, cursor);
+	}
+}
+
+fn step(from: &mut Vec<(String, usize)>, to: &mut Vec<(String, usize)>, inp: &mut String, cursor: &mut usize) {
+	if let Some((line, position)) = from.pop() {
+		to.push((std::mem::replace(inp, line), *cursor));
+		*cursor = position.min(inp.len());
+	}
+}
+
+// Swaps the character before the cursor with the one under it, or the last two
+// at the end of the line, and returns the new cursor position (Ctrl-T)
+pub fn transpose(inp: &mut String, pos: usize) -> Option<usize> {
+	let pos = if pos == inp.len() { previous_grapheme(inp, pos) } else { pos };
+	let before = previous_grapheme(inp, pos);
+	let after = next_grapheme(inp, pos);
+	if before == pos || after == pos {
+		return None;
+	}
+	let swapped = format!("{}{}", &inp[pos..after], &inp[before..pos]);
+	inp.replace_range(before..after, &swapped);
+	return Some(after);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn words() {
+		let inp = "echo foo_bar-baz  qux";
+		assert_eq!(word_start(inp, inp.len()), 18);
+		assert_eq!(word_start(inp, 17), 13);
+		assert_eq!(word_start(inp, 13), 5);
+		assert_eq!(word_end(inp, 0), 4);
+		assert_eq!(word_end(inp, 4), 12);
+		assert_eq!(word_end(inp, 18), inp.len());
+		assert_eq!(big_word_start(inp, inp.len()), 18);
+		assert_eq!(big_word_start(inp, 17), 5);
+		assert_eq!(big_word_start("a", 1), 0);
+	}
+
+	#[test]
+	fn transposing() {
+		let mut inp = "abc".to_string();
+		assert_eq!(transpose(&mut inp, 1), Some(2));
+		assert_eq!(inp, "bac");
+		assert_eq!(transpose(&mut inp, 3), Some(3));
+		assert_eq!(inp, "bca");
+		let mut inp = "é1".to_string();
+		assert_eq!(transpose(&mut inp, 3), Some(3));
+		assert_eq!(inp, "1é");
+		assert_eq!(transpose(&mut "a".to_string(), 1), None);
+		assert_eq!(transpose(&mut "ab".to_string(), 0), None);
+	}
+
+	#[test]
+	fn killing() {
+		let _lock = kill_ring_test.lock().unwrap();
+		let mut inp = "one two three".to_string();
+		kill(&mut inp, 8, 13, false, false);
+		kill(&mut inp, 4, 8, true, true);
+		assert_eq!(inp, "one ");
+		assert_eq!(yank().unwrap(), "two three");
+		kill(&mut inp, 0, 4, false, false);
+		assert_eq!(inp, "");
+		assert_eq!(yank().unwrap(), "one ");
+		assert_eq!(yank_pop().unwrap(), "two three");
+		assert_eq!(yank_pop().unwrap(), "one ");
+		for i in 0..KILL_RING_SIZE {
+			save(&i.to_string());
+		}
+		assert!(kill_ring.lock().unwrap().len() == KILL_RING_SIZE);
+		kill(&mut inp, 0, 0, false, false);
+		assert_eq!(yank().unwrap(), (KILL_RING_SIZE - 1).to_string());
+	}
+
+	#[test]
+	fn graphemes() {
+		// e and a combining acute accent, then a flag made of two code points
+		let inp = "ae\u{301}\u{1F1EB}\u{1F1F7}b";
+		assert_eq!(next_grapheme(inp, 1), 4);
+		assert_eq!(next_grapheme(inp, 4), 12);
+		assert_eq!(previous_grapheme(inp, 12), 4);
+		assert_eq!(previous_grapheme(inp, 4), 1);
+		assert_eq!(previous_grapheme(inp, 0), 0);
+		assert_eq!(next_grapheme(inp, inp.len()), inp.len());
+		assert_eq!(width("e\u{301}"), 1);
+		assert_eq!(width("日本"), 4);
+	}
+
+	#[test]
+	fn undo_and_redo() {
+		let mut undo = Undo::new();
+		let (mut inp, mut cursor) = ("ab".to_string(), 2);
+		undo.save(&inp, cursor);
+		inp.push('c');
+		cursor = 3;
+		undo.save(&inp, cursor);
+		inp.clear();
+		cursor = 0;
+
+		undo.undo(&mut inp, &mut cursor);
+		assert_eq!((inp.as_str(), cursor), ("abc", 3));
+		undo.undo(&mut inp, &mut cursor);
+		assert_eq!((inp.as_str(), cursor), ("ab", 2));
+		undo.undo(&mut inp, &mut cursor);
+		assert_eq!((inp.as_str(), cursor), ("ab", 2));
+		undo.redo(&mut inp, &mut cursor);
+		assert_eq!((inp.as_str(), cursor), ("abc", 3));
+		undo.redo(&mut inp, &mut cursor);
+		assert_eq!((inp.as_str(), cursor), ("", 0));
+
+		// A new change drops what was undone
+		undo.undo(&mut inp, &mut cursor);
+		undo.save(&inp, cursor);
+		undo.redo(&mut inp, &mut cursor);
+		assert_eq!(inp, "abc");
+	}
+}
@@ -0,0 +1,145 @@
+#![allow(non_upper_case_globals)]
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use lazy_static::lazy_static;
+
+use crate::commands::Command;
+use crate::parser::Statement;
+
+lazy_static! {
+	// Functions defined with `fn name(params) { ... }` or `name() { ... }`
+	pub static ref defined: Mutex<HashMap<String, Function>> = Mutex::new(HashMap::new());
+}
+
+thread_local! {
+	// The function call running on this thread. The threads of a pipeline
+	// started inside it are given the same one
+	static current: RefCell<Option<Arc<Mutex<Scope>>>> = const { RefCell::new(None) };
+}
+
+// Deep enough for recursion, but stops a function that calls itself forever
+// before the stack runs out
+const MAX_DEPTH: usize = 100;
+
+#[derive(Clone)]
+pub struct Function {
+	pub params: Vec<String>,
+	pub body: Vec<Statement>,
+	// The script file and first line of the chunk it was defined in
+	pub location: Option<(String, usize)>,
+}
+
+// The variables of one function call, read before the global ones. Functions
+// in a pipeline run at the same time, so each call has its own
+pub struct Scope {
+	variables: HashMap<String, String>,
+	// How many function calls are running inside each other
+	depth: usize,
+}
+
+pub fn scope() -> Option<Arc<Mutex<Scope>>> {
+	return current.with(|x| x.borrow().clone());
+}
+
+// Makes `scope` the one of this thread and returns the one it had before
+pub fn enter(scope: Option<Arc<Mutex<Scope>>>) -> Option<Arc<Mutex<Scope>>> {
+	return current.with(|x| x.replace(scope));
+}
+
+// The variables of the function call running on this thread
+pub fn variables() -> Vec<(String, String)> {
+	return match scope() {
+		Some(o) => o.lock().unwrap().variables.iter().map(|(name, value)| (name.clone(), value.clone())).collect(),
+		None => Vec::new(),
+	};
+}
+
+// Changes a variable of the function call running on this thread. Returns
+// false when it has no variable with that name
+pub fn set(name: &str, value: &str) -> bool {
+	let scope = match scope() {
+		Some(o) => o,
+		None => return false,
+	};
+	let mut s = scope.lock().unwrap();
+	return match s.variables.get_mut(name) {
+		Some(o) => {
+			*o = value.to_string();
+			true
+		},
+		None => false,
+	};
+}
+
+// Gives back the caller's scope and script location once a call is done, even
+// when its body panicked
+struct Call {
+	outer: Option<Arc<Mutex<Scope>>>,
+	location: Option<(String, usize, usize)>,
+}
+
+impl Drop for Call {
+	fn drop(&mut self) {
+		enter(self.outer.take());
+		*crate::script::location.lock().unwrap() = self.location.take();
+	}
+}
+
+// Saves a definition, along with where it was read so errors in its body
+// point at the right file and line
+pub fn define(name: String, params: Vec<String>, body: Vec<Statement>) {
+	let location = crate::script::location.lock().unwrap().as_ref().map(|x| (x.0.clone(), x.1));
+	defined.lock().unwrap().insert(name, Function { params, body, location });
+}
+
+pub fn exists(name: &str) -> bool {
+	return defined.lock().unwrap().contains_key(name);
+}
+
+// The functions as name and description, for `help`
+pub fn describe() -> Vec<(String, String)> {
+	return defined.lock().unwrap().iter().map(|(name, f)| {
+		let params: Vec<String> = f.params.iter().map(|x| format!("{{{}}}", x)).collect();
+		if params.is_empty() {
+			return (name.clone(), "User-defined function".to_string());
+		}
+		return (name.clone(), format!("User-defined function, takes {}", params.join(" ")));
+	}).collect();
+}
+
+// Runs a function's body with its arguments as variables of its own: by
+// parameter name, as $1$, $2$ and so on, and their count as $#$. The caller's
+// variables can still be read, but changing them only lasts for this call.
+// Returns the body's status
+pub fn call(args: &[String], cmds: &[Command<'static>], streams: &crate::redirect::Streams) -> Result<i32, String> {
+	let function = match defined.lock().unwrap().get(&args[0]) {
+		Some(o) => o.clone(),
+		None => return Err(format!("{} is not a function", args[0])),
+	};
+	let (mut variables, depth) = match scope() {
+		Some(o) => {
+			let s = o.lock().unwrap();
+			(s.variables.clone(), s.depth)
+		},
+		None => (HashMap::new(), 0),
+	};
+	if depth >= MAX_DEPTH {
+		return Err(format!("{}: functions are nested more than {} deep", args[0], MAX_DEPTH));
+	}
+
+	for (i, param) in function.params.iter().enumerate() {
+		variables.insert(param.clone(), args.get(i + 1).cloned().unwrap_or_default());
+	}
+	for (i, arg) in args.iter().enumerate().skip(1) {
+		variables.insert(i.to_string(), arg.clone());
+	}
+	variables.insert("#".to_string(), (args.len() - 1).to_string());
+
+	let _call = Call {
+		outer: enter(Some(Arc::new(Mutex::new(Scope { variables, depth: depth + 1 })))),
+		location: std::mem::replace(&mut *crate::script::location.lock().unwrap(), function.location.map(|(file, start)| (file, start, 0))),
+	};
+	return Ok(crate::run_parsed(&function.body, cmds, streams));
+}
@@ -0,0 +1,160 @@
+use std::path::Path;
+
+use crate::commands::Command;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Class {
+	Plain,
+	Command,
+	Unknown,
+	Quoted,
+	Variable,
+	Operator,
+	Keyword,
+	Comment,
+}
+
+// The text of a word without its quotes and backslashes. Unlike the tokenizer
+// it also accepts words that are still being typed, like an unclosed quote
+fn unquote(word: &str) -> String {
+	if let Ok(tokens) = crate::parser::tokenize(word) {
+		if let Some(crate::parser::Token::Word(w)) = tokens.into_iter().next() {
+			return w.text;
+		}
+	}
+	return word.chars().filter(|x| !"'\"\\".contains(*x)).collect();
+}
+
+// Colors the line being edited: the command name green when it is a builtin,
+// a function, an alias or an executable and red when it is not, quotes yellow,
+// `$name$` variables cyan, operators magenta, keywords like if and for bold blue,
+// comments dim and words naming an existing file or directory underlined
+pub fn highlight(inp: &str, cmds: &[Command<'static>]) -> String {
+	let chars: Vec<char> = inp.chars().collect();
+	let mut classes: Vec<Class> = vec![Class::Plain; chars.len()];
+	let mut underlined: Vec<bool> = vec![false; chars.len()];
+	let mut command_expected = true;
+	let mut i = 0;
+	while i < chars.len() {
+		let c = chars[i];
+		if c.is_whitespace() {
+			if c == '\n' {
+				command_expected = true;
+			}
+			i += 1;
+			continue;
+		}
+		if c == '#' {
+			while i < chars.len() && chars[i] != '\n' {
+				classes[i] = Class::Comment;
+				i += 1;
+			}
+			continue;
+		}
+		if crate::parser::is_operator(c) {
+			classes[i] = Class::Operator;
+			// The '&' of "2>&1" does not start a new command
+			if c != '<' && c != '>' && !(c == '&' && i > 0 && chars[i - 1] == '>') {
+				command_expected = true;
+			}
+			i += 1;
+			continue;
+		}
+
+		let start = i;
+		while i < chars.len() && !chars[i].is_whitespace() && !crate::parser::is_operator(chars[i]) {
+			match chars[i] {
+				q @ ('\'' | '"') => {
+					classes[i] = Class::Quoted;
+					i += 1;
+					while i < chars.len() && chars[i] != q {
+						if q == '"' && chars[i] == '\\' && i + 1 < chars.len() {
+							classes[i] = Class::Quoted;
+							i += 1;
+						}
+						classes[i] = Class::Quoted;
+						i += 1;
+					}
+					if i < chars.len() {
+						classes[i] = Class::Quoted;
+					}
+				},
+				'\\' => i += 1,
+				_ => (),
+			}
+			i += 1;
+		}
+		let end = i.min(chars.len());
+
+		// Variables are replaced everywhere in the line, even inside quotes
+		let mut j = start;
+		while j < end {
+			if chars[j] == '$' {
+				let close = if chars.get(j + 1) == Some(&'?') {
+					// $? and $?$ are the last status
+					if chars.get(j + 2) == Some(&'$') { j + 3 } else { j + 2 }
+				} else {
+					(j + 1..end).find(|x| chars[*x] == '$').map(|x| x + 1).unwrap_or(end)
+				}.min(end);
+				for class in classes[j..close].iter_mut() {
+					*class = Class::Variable;
+				}
+				j = close;
+			} else {
+				j += 1;
+			}
+		}
+
+		let word: String = chars[start..end].iter().collect();
+		let text = unquote(&word);
+		let keyword = word == text && (crate::parser::starts_command(&text, command_expected) || (command_expected && ["fn", "for", "break", "continue"].contains(&text.as_str())));
+		if keyword {
+			for c in classes[start..end].iter_mut() {
+				*c = Class::Keyword;
+			}
+			command_expected = crate::parser::starts_command(&text, command_expected);
+		} else if command_expected {
+			let known = crate::resolve_command(&text, cmds).is_some() || crate::commands::aliases.lock().unwrap().contains_key(&text);
+			let class = if known { Class::Command } else { Class::Unknown };
+			for c in classes[start..end].iter_mut() {
+				*c = class;
+			}
+			command_expected = false;
+		} else if !text.is_empty() && Path::new(&text).exists() {
+			for u in underlined[start..end].iter_mut() {
+				*u = true;
+			}
+		}
+	}
+
+	let mut out = String::new();
+	let mut run = String::new();
+	for (k, c) in chars.iter().enumerate() {
+		run.push(*c);
+		// Newlines stay unstyled, the continuation prompt is printed after them
+		let same = k + 1 < chars.len() && chars[k + 1] != '\n' && *c != '\n' && classes[k + 1] == classes[k] && underlined[k + 1] == underlined[k];
+		if same {
+			continue;
+		}
+		if *c == '\n' {
+			out.push_str(&std::mem::take(&mut run));
+			continue;
+		}
+		let mut styled = console::style(std::mem::take(&mut run));
+		styled = match classes[k] {
+			Class::Plain => styled,
+			Class::Command => styled.green(),
+			Class::Unknown => styled.red(),
+			Class::Quoted => styled.yellow(),
+			Class::Variable => styled.cyan(),
+			Class::Operator => styled.magenta(),
+			Class::Keyword => styled.blue().bold(),
+			Class::Comment => styled.dim(),
+		};
+		if underlined[k] {
+			styled = styled.underlined();
+		}
+		out.push_str(&styled.to_string());
+	}
+	return out;
+}
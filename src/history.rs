@@ -0,0 +1,358 @@
+#![allow(non_upper_case_globals)]
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use lazy_static::lazy_static;
+
+lazy_static! {
+	pub static ref entries: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+}
+
+const DEFAULT_SIZE: usize = 1000;
+
+#[derive(Clone, PartialEq)]
+pub struct Entry {
+	// Seconds since the epoch, 0 when unknown
+	pub time: i64,
+	// The directory the command was run in, empty when unknown
+	pub dir: String,
+	pub line: String,
+}
+
+// The history file lives in the user's data directory,
+// e.g. ~/.local/share/terminal/history on Linux
+pub fn path() -> Option<PathBuf> {
+	return dirs::data_dir().map(|x| x.join("terminal").join("history"));
+}
+
+// How many entries are kept, from `set HISTSIZE n` or the HISTSIZE environment variable
+pub fn size() -> usize {
+	let value = match crate::commands::data.lock().unwrap().get("HISTSIZE") {
+		Some(o) => Some(o.clone()),
+		None => std::env::var("HISTSIZE").ok(),
+	};
+	return match value.map(|x| x.trim().parse::<usize>()) {
+		Some(Ok(o)) => o,
+		_ => DEFAULT_SIZE,
+	};
+}
+
+fn current_dir() -> String {
+	return std::env::current_dir().map(|x| x.display().to_string()).unwrap_or_default();
+}
+
+// The most recent longer entry starting with `inp` for autosuggestions,
+// preferring ones run in the current directory
+pub fn suggest(inp: &str) -> Option<String> {
+	if inp.is_empty() {
+		return None;
+	}
+	let dir = current_dir();
+	let e = entries.lock().unwrap();
+	let mut matches = e.iter().rev().filter(|x| x.line.len() > inp.len() && x.line.starts_with(inp));
+	let first = matches.clone().next()?;
+	let found = matches.find(|x| x.dir == dir).unwrap_or(first);
+	return Some(found.line[inp.len()..].to_string());
+}
+
+pub fn lines() -> Vec<String> {
+	return entries.lock().unwrap().iter().map(|x| x.line.clone()).collect();
+}
+
+// Each entry is stored as a "#<time> <directory>" line followed by the command,
+// with newlines, backslashes and a leading '#' escaped
+fn escape(line: &str) -> String {
+	let escaped = line.replace('\\', "\\\\").replace('\n', "\\n");
+	if escaped.starts_with('#') {
+		return format!("\\{}", escaped);
+	}
+	return escaped;
+}
+
+fn unescape(line: &str) -> String {
+	let mut entry = String::new();
+	let mut chars = line.chars();
+	while let Some(c) = chars.next() {
+		if c != '\\' {
+			entry.push(c);
+			continue;
+		}
+		match chars.next() {
+			Some('n') => entry.push('\n'),
+			Some(n) => entry.push(n),
+			None => entry.push('\\'),
+		}
+	}
+	return entry;
+}
+
+fn read_entries(file: &mut File) -> Result<Vec<Entry>, std::io::Error> {
+	let mut bytes: Vec<u8> = Vec::new();
+	file.read_to_end(&mut bytes)?;
+	let mut result: Vec<Entry> = Vec::new();
+	let mut time: i64 = 0;
+	let mut dir = String::new();
+	for line in String::from_utf8_lossy(&bytes).lines() {
+		if let Some(info) = line.strip_prefix('#') {
+			let (t, d) = info.split_once(' ').unwrap_or((info, ""));
+			time = t.trim().parse().unwrap_or(0);
+			dir = unescape(d);
+		} else if !line.is_empty() {
+			result.push(Entry { time, dir: std::mem::take(&mut dir), line: unescape(line) });
+			time = 0;
+		}
+	}
+	return Ok(result);
+}
+
+fn write_entry(file: &mut File, entry: &Entry) -> Result<(), std::io::Error> {
+	return writeln!(file, "#{} {}\n{}", entry.time, entry.dir.replace('\\', "\\\\").replace('\n', "\\n"), escape(&entry.line));
+}
+
+fn keep_last(list: &mut Vec<Entry>, size: usize) {
+	if list.len() > size {
+		list.drain(..list.len() - size);
+	}
+}
+
+pub fn load() {
+	let path = match path() {
+		Some(o) => o,
+		None => return,
+	};
+	let mut file = match File::open(&path) {
+		Ok(o) => o,
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+		Err(e) => {
+			crate::print_error(line!(), format!("Cannot read history file {}: {}", path.display(), e));
+			return;
+		}
+	};
+	let mut loaded = match file.lock_shared().and_then(|_| read_entries(&mut file)) {
+		Ok(o) => o,
+		Err(e) => {
+			crate::print_error(line!(), format!("Cannot read history file {}: {}", path.display(), e));
+			return;
+		}
+	};
+	keep_last(&mut loaded, size());
+	*entries.lock().unwrap() = loaded;
+}
+
+// Adds an accepted line to the history and writes it to the history file right
+// away. The file is locked while it is changed, so several sessions can share it
+pub fn add(line: &str) {
+	let entry = Entry {
+		time: chrono::Local::now().timestamp(),
+		dir: current_dir(),
+		line: line.to_string(),
+	};
+	let size = size();
+	{
+		let mut e = entries.lock().unwrap();
+		if e.last().is_some_and(|x| x.line == line) {
+			return;
+		}
+		e.push(entry.clone());
+		keep_last(&mut e, size);
+	}
+	if size == 0 {
+		return;
+	}
+	if let Err(e) = update_file(|file, list| {
+		write_entry(file, &entry)?;
+		list.push(entry);
+		return Ok(list.len() > size);
+	}) {
+		crate::print_error(line!(), format!("Cannot write to history file: {}", e));
+	}
+}
+
+// Removes the entry with the given 1-based number, here and in the history file
+pub fn delete(number: usize) -> Result<(), String> {
+	let removed = {
+		let mut e = entries.lock().unwrap();
+		if number == 0 || number > e.len() {
+			return Err(format!("{}: history position out of range", number));
+		}
+		e.remove(number - 1)
+	};
+	return update_file(|_, list| {
+		if let Some(i) = list.iter().rposition(|x| *x == removed) {
+			list.remove(i);
+			return Ok(true);
+		}
+		return Ok(false);
+	}).map_err(|e| format!("Cannot write to history file: {}", e));
+}
+
+pub fn clear() -> Result<(), String> {
+	entries.lock().unwrap().clear();
+	return update_file(|_, list| {
+		list.clear();
+		return Ok(true);
+	}).map_err(|e| format!("Cannot write to history file: {}", e));
+}
+
+// Locks the history file and lets `change` append to it or edit its entries.
+// The file is rewritten from the edited entries, trimmed to the size limit,
+// when `change` returns true
+fn update_file<F>(change: F) -> Result<(), std::io::Error>
+where F: FnOnce(&mut File, &mut Vec<Entry>) -> Result<bool, std::io::Error> {
+	let path = match path() {
+		Some(o) => o,
+		None => return Ok(()),
+	};
+	if let Some(dir) = path.parent() {
+		std::fs::create_dir_all(dir)?;
+	}
+	let mut file = OpenOptions::new().read(true).append(true).create(true).open(&path)?;
+	file.lock()?;
+	file.seek(SeekFrom::Start(0))?;
+	let mut list = read_entries(&mut file)?;
+	if change(&mut file, &mut list)? {
+		keep_last(&mut list, size());
+		// Writes always go to the end in append mode, which is the start once truncated
+		file.set_len(0)?;
+		for e in list.iter() {
+			write_entry(&mut file, e)?;
+		}
+	}
+	return Ok(());
+}
+
+// The raw text of the last word of a command line, for `!$`
+fn last_word(line: &str) -> String {
+	let last = match crate::parser::words(line) {
+		Ok(o) => o.last().map(|w| line[w.start..w.end].to_string()),
+		Err(_) => line.split_whitespace().last().map(|x| x.to_string()),
+	};
+	return last.unwrap_or_default();
+}
+
+fn find_event(designator: &str, list: &[String]) -> Result<String, String> {
+	let found = if designator == "!" || designator == "$" {
+		list.last()
+	} else if let Ok(n) = designator.parse::<i64>() {
+		if n < 0 {
+			list.len().checked_sub(n.unsigned_abs() as usize).and_then(|x| list.get(x))
+		} else {
+			(n as usize).checked_sub(1).and_then(|x| list.get(x))
+		}
+	} else {
+		list.iter().rev().find(|x| x.starts_with(designator))
+	};
+	return match found {
+		Some(o) if designator == "$" => Ok(last_word(o)),
+		Some(o) => Ok(o.clone()),
+		None => Err(format!("!{}: event not found", designator)),
+	};
+}
+
+// Replaces `!!`, `!n`, `!-n`, `!prefix` and `!$` with earlier commands. Nothing
+// is expanded inside single quotes or after a backslash
+pub fn expand(inp: &str) -> Result<String, String> {
+	if !inp.contains('!') {
+		return Ok(inp.to_string());
+	}
+	let list = lines();
+	let mut result = String::new();
+	let mut chars = inp.chars().peekable();
+	let mut single = false;
+	let mut double = false;
+	while let Some(c) = chars.next() {
+		match c {
+			'\'' if !double => single = !single,
+			'"' if !single => double = !double,
+			'\\' if !single => {
+				result.push(c);
+				if let Some(n) = chars.next() {
+					result.push(n);
+				}
+				continue;
+			},
+			'!' if !single => {
+				let mut designator = String::new();
+				if let Some(n) = chars.next_if(|x| *x == '!' || *x == '$') {
+					designator.push(n);
+				} else {
+					if let Some(n) = chars.next_if(|x| *x == '-') {
+						designator.push(n);
+					}
+					while let Some(n) = chars.next_if(|x| !x.is_whitespace() && !";=|&<>()'\"!".contains(*x)) {
+						designator.push(n);
+					}
+				}
+				if designator.is_empty() || designator == "-" {
+					result.push(c);
+					result.push_str(&designator);
+				} else {
+					result.push_str(&find_event(&designator, &list)?);
+				}
+				continue;
+			},
+			_ => (),
+		}
+		result.push(c);
+	}
+	return Ok(result);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn escaping() {
+		for line in ["echo a", "#not a header", "echo a\necho b", "echo a\\nb", "\\#x", "a\\"] {
+			let escaped = escape(line);
+			assert!(!escaped.contains('\n'));
+			assert!(!escaped.starts_with('#'));
+			assert_eq!(unescape(&escaped), line);
+		}
+	}
+
+	#[test]
+	fn file_roundtrip() {
+		let path = std::env::temp_dir().join(format!("terminal-history-{}", std::process::id()));
+		let written = vec![
+			Entry { time: 10, dir: "/a dir".to_string(), line: "echo a\necho b".to_string() },
+			Entry { time: 0, dir: String::new(), line: "#x".to_string() },
+		];
+		let mut file = OpenOptions::new().create(true).truncate(true).read(true).write(true).open(&path).unwrap();
+		for entry in &written {
+			write_entry(&mut file, entry).unwrap();
+		}
+		file.seek(SeekFrom::Start(0)).unwrap();
+		let read = read_entries(&mut file).unwrap();
+		std::fs::remove_file(&path).unwrap();
+		assert!(read == written);
+
+		let mut list = written.clone();
+		keep_last(&mut list, 1);
+		assert!(list[..] == written[1..]);
+	}
+
+	#[test]
+	fn last_argument() {
+		let list = vec!["ls /tmp".to_string(), "cp a 'b c'".to_string()];
+		assert_eq!(find_event("$", &list).unwrap(), "'b c'");
+		assert_eq!(find_event("!", &list).unwrap(), "cp a 'b c'");
+		assert_eq!(find_event("-2", &list).unwrap(), "ls /tmp");
+		assert_eq!(find_event("l", &list).unwrap(), "ls /tmp");
+		assert_eq!(last_word("echo a > f"), "f");
+		assert_eq!(last_word("echo a &"), "a");
+		assert!(find_event("$", &[]).is_err());
+	}
+
+	#[test]
+	fn bang_expansion() {
+		*entries.lock().unwrap() = vec![Entry { time: 0, dir: String::new(), line: "mkdir \"new dir\"".to_string() }];
+		assert_eq!(expand("cd !$").unwrap(), "cd \"new dir\"");
+		assert_eq!(expand("!! && ls !$").unwrap(), "mkdir \"new dir\" && ls \"new dir\"");
+		assert_eq!(expand("echo '!$' \\!$ a!").unwrap(), "echo '!$' \\!$ a!");
+		assert!(expand("!nothing").is_err());
+	}
+}
@@ -0,0 +1,645 @@
+#![allow(non_upper_case_globals)]
+
+use std::collections::HashMap;
+use std::process::Child;
+use std::sync::{mpsc::{Receiver, Sender}, Mutex};
+use lazy_static::lazy_static;
+
+lazy_static! {
+	pub static ref table: Mutex<Vec<Job>> = Mutex::new(Vec::new());
+	static ref interactive: Mutex<bool> = Mutex::new(false);
+	static ref shell_pgid: Mutex<u32> = Mutex::new(0);
+	#[cfg(unix)]
+	static ref shell_tmodes: Mutex<Option<libc::termios>> = Mutex::new(None);
+	// Where Ctrl-C is delivered while something runs in the foreground
+	static ref foreground_events: Mutex<Option<Sender<Event>>> = Mutex::new(None);
+	// Statuses collected by watchers while nobody was waiting for their job
+	static ref reaped: Mutex<HashMap<u32, ProcState>> = Mutex::new(HashMap::new());
+	// Where each watched process reports its state changes. A process keeps one
+	// watcher until it exits, which reports to whoever waits for its job now
+	static ref listeners: Mutex<HashMap<u32, Sender<Event>>> = Mutex::new(HashMap::new());
+}
+
+// Everything a foreground wait can be woken up by
+pub enum Event {
+	Interrupt,
+	Builtin(usize),
+	Process(u32, ProcState),
+	// Periodic wake up on platforms where processes cannot be watched
+	#[cfg_attr(unix, allow(dead_code))]
+	Tick,
+}
+
+#[cfg(not(unix))]
+const SIGKILL: i32 = 9;
+#[cfg(unix)]
+const SIGINT_STATUS: i32 = 128 + libc::SIGINT;
+#[cfg(not(unix))]
+const SIGINT_STATUS: i32 = -1;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ProcState {
+	Running,
+	Stopped,
+	Exited(i32),
+}
+
+pub struct Process {
+	pub child: Child,
+	pub state: ProcState,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum JobState {
+	Running,
+	Stopped,
+	Done(i32),
+}
+
+impl std::fmt::Display for JobState {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		return match self {
+			JobState::Running => write!(f, "Running"),
+			JobState::Stopped => write!(f, "Stopped"),
+			JobState::Done(0) => write!(f, "Done"),
+			#[cfg(unix)]
+			JobState::Done(code) if *code == 128 + libc::SIGINT => write!(f, "Interrupt"),
+			#[cfg(unix)]
+			JobState::Done(code) if *code == 128 + libc::SIGKILL => write!(f, "Killed"),
+			#[cfg(unix)]
+			JobState::Done(code) if *code == 128 + libc::SIGTERM => write!(f, "Terminated"),
+			JobState::Done(code) => write!(f, "Exit {}", code),
+		};
+	}
+}
+
+pub struct Job {
+	pub id: usize,
+	pub pgid: u32,
+	pub command: String,
+	pub processes: Vec<Process>,
+	#[cfg(unix)]
+	pub tmodes: Option<libc::termios>,
+}
+
+impl Job {
+	pub fn new(command: String) -> Job {
+		return Job {
+			id: 0,
+			pgid: 0,
+			command,
+			processes: Vec::new(),
+			#[cfg(unix)]
+			tmodes: None,
+		};
+	}
+
+	pub fn push(&mut self, child: Child) {
+		if self.processes.is_empty() {
+			self.pgid = child.id();
+		}
+		self.processes.push(Process { child, state: ProcState::Running });
+	}
+
+	// Collects any state changes without blocking and returns the job's overall state
+	pub fn poll(&mut self) -> Result<JobState, String> {
+		for p in self.processes.iter_mut() {
+			if let ProcState::Exited(_) = p.state {
+				continue;
+			}
+			if let Some(s) = poll_process(&mut p.child)? {
+				p.state = s;
+			}
+		}
+		return Ok(self.state());
+	}
+
+	// Whether one of the job's processes was terminated by SIGINT instead of handling it
+	pub fn interrupted(&self) -> bool {
+		return self.processes.iter().any(|x| x.state == ProcState::Exited(SIGINT_STATUS));
+	}
+
+	pub fn update(&mut self, pid: u32, state: ProcState) {
+		if let Some(p) = self.processes.iter_mut().find(|x| x.child.id() == pid) {
+			p.state = state;
+		}
+	}
+
+	// Sends the state changes of the job's processes to `events` from now on
+	pub fn watch(&self, events: &Sender<Event>) {
+		for p in self.processes.iter() {
+			if !matches!(p.state, ProcState::Exited(_)) {
+				watch(p.child.id(), events);
+			}
+		}
+	}
+
+	pub fn state(&self) -> JobState {
+		if self.processes.iter().any(|x| x.state == ProcState::Running) {
+			return JobState::Running;
+		}
+		if self.processes.iter().any(|x| x.state == ProcState::Stopped) {
+			return JobState::Stopped;
+		}
+		return match self.processes.last() {
+			Some(Process { state: ProcState::Exited(code), .. }) => JobState::Done(*code),
+			_ => JobState::Done(0),
+		};
+	}
+}
+
+pub fn is_interactive() -> bool {
+	return *interactive.lock().unwrap();
+}
+
+// Puts the shell in its own process group in control of the terminal and
+// ignores the job control signals so only foreground jobs receive them
+#[cfg(unix)]
+pub fn init() {
+	unsafe {
+		if libc::isatty(libc::STDIN_FILENO) != 1 {
+			return;
+		}
+		loop {
+			let pgrp = libc::getpgrp();
+			if libc::tcgetpgrp(libc::STDIN_FILENO) == pgrp {
+				break;
+			}
+			libc::kill(-pgrp, libc::SIGTTIN);
+		}
+		libc::signal(libc::SIGTSTP, libc::SIG_IGN);
+		libc::signal(libc::SIGTTIN, libc::SIG_IGN);
+		libc::signal(libc::SIGTTOU, libc::SIG_IGN);
+
+		let pid = libc::getpid();
+		if libc::getpgrp() != pid && libc::setpgid(pid, pid) < 0 {
+			crate::print_error(line!(), format!("Could not create a process group: {}", std::io::Error::last_os_error()));
+			return;
+		}
+		libc::tcsetpgrp(libc::STDIN_FILENO, pid);
+		*shell_pgid.lock().unwrap() = pid as u32;
+
+		let mut tmodes: libc::termios = std::mem::zeroed();
+		if libc::tcgetattr(libc::STDIN_FILENO, &mut tmodes) == 0 {
+			*shell_tmodes.lock().unwrap() = Some(tmodes);
+		}
+	}
+	*interactive.lock().unwrap() = true;
+}
+
+#[cfg(not(unix))]
+pub fn init() {}
+
+// Makes a spawned child join the job's process group (a new one when `pgid` is 0)
+// and, for foreground jobs, take the terminal before it starts running
+#[cfg(unix)]
+pub fn prepare_child(c: &mut std::process::Command, pgid: u32, foreground: bool) {
+	use std::os::unix::process::CommandExt;
+	if !is_interactive() {
+		return;
+	}
+	unsafe {
+		c.pre_exec(move || {
+			let pid = libc::getpid();
+			let pgid = if pgid == 0 { pid } else { pgid as libc::pid_t };
+			libc::setpgid(pid, pgid);
+			if foreground {
+				libc::tcsetpgrp(libc::STDIN_FILENO, pgid);
+			}
+			libc::signal(libc::SIGTSTP, libc::SIG_DFL);
+			libc::signal(libc::SIGTTIN, libc::SIG_DFL);
+			libc::signal(libc::SIGTTOU, libc::SIG_DFL);
+			return Ok(());
+		});
+	}
+}
+
+#[cfg(not(unix))]
+pub fn prepare_child(_: &mut std::process::Command, _: u32, _: bool) {}
+
+#[cfg(unix)]
+fn poll_process(c: &mut Child) -> Result<Option<ProcState>, String> {
+	if let Some(s) = reaped.lock().unwrap().remove(&c.id()) {
+		return Ok(Some(s));
+	}
+	let mut status: libc::c_int = 0;
+	let r = unsafe { libc::waitpid(c.id() as libc::pid_t, &mut status, libc::WNOHANG | libc::WUNTRACED | libc::WCONTINUED) };
+	if r < 0 {
+		let e = std::io::Error::last_os_error();
+		// Its watcher collected it first and stored it in `reaped` or sent it
+		if e.raw_os_error() == Some(libc::ECHILD) {
+			return Ok(None);
+		}
+		return Err(format!("Error getting status of process: {}", e));
+	}
+	if r == 0 {
+		return Ok(None);
+	}
+	return Ok(Some(decode_status(status)));
+}
+
+#[cfg(unix)]
+fn decode_status(status: libc::c_int) -> ProcState {
+	if libc::WIFSTOPPED(status) {
+		return ProcState::Stopped;
+	}
+	if libc::WIFCONTINUED(status) {
+		return ProcState::Running;
+	}
+	// Processes killed by a signal report 128 + the signal number, like other shells
+	if libc::WIFSIGNALED(status) {
+		return ProcState::Exited(128 + libc::WTERMSIG(status));
+	}
+	return ProcState::Exited(libc::WEXITSTATUS(status));
+}
+
+// Makes `events` the listener of a process, starting a thread that blocks in
+// waitpid if it has none yet. Watchers never wait for the same process twice,
+// so a status cannot be collected by a leftover one and lost
+#[cfg(unix)]
+fn watch(pid: u32, events: &Sender<Event>) {
+	if listeners.lock().unwrap().insert(pid, events.clone()).is_some() {
+		return;
+	}
+	std::thread::spawn(move || {
+		let mut status: libc::c_int = 0;
+		loop {
+			let r = unsafe { libc::waitpid(pid as libc::pid_t, &mut status, libc::WUNTRACED | libc::WCONTINUED) };
+			if r < 0 {
+				if std::io::Error::last_os_error().raw_os_error() == Some(libc::EINTR) {
+					continue;
+				}
+				// Polling collected the exit already, or an earlier watcher did
+				// and left it in `reaped` before this one was started
+				let mut l = listeners.lock().unwrap();
+				if let (Some(state), Some(events)) = (reaped.lock().unwrap().remove(&pid), l.get(&pid)) {
+					let _ = events.send(Event::Process(pid, state));
+				}
+				l.remove(&pid);
+				return;
+			}
+			let state = decode_status(status);
+			let mut l = listeners.lock().unwrap();
+			let sent = l.get(&pid).is_some_and(|x| x.send(Event::Process(pid, state)).is_ok());
+			if !sent {
+				reaped.lock().unwrap().insert(pid, state);
+			}
+			if let ProcState::Exited(_) = state {
+				l.remove(&pid);
+				return;
+			}
+		}
+	});
+}
+
+#[cfg(not(unix))]
+fn watch(_: u32, _: &Sender<Event>) {}
+
+// Blocks until the next event, falling back to polling the job where its
+// processes cannot be watched
+#[cfg(unix)]
+pub fn next_event(events: &Receiver<Event>) -> Option<Event> {
+	return events.recv().ok();
+}
+
+#[cfg(not(unix))]
+pub fn next_event(events: &Receiver<Event>) -> Option<Event> {
+	return match events.recv_timeout(std::time::Duration::from_millis(50)) {
+		Ok(o) => Some(o),
+		Err(std::sync::mpsc::RecvTimeoutError::Timeout) => Some(Event::Tick),
+		Err(_) => None,
+	};
+}
+
+// Returns the channel of the pipeline that was in the foreground before, which
+// is still waiting when a builtin like `source` runs commands itself
+pub fn set_foreground(events: Option<Sender<Event>>) -> Option<Sender<Event>> {
+	return std::mem::replace(&mut *foreground_events.lock().unwrap(), events);
+}
+
+// Called from the Ctrl-C handler; does nothing while the prompt is idle
+pub fn interrupt_foreground() {
+	if let Some(events) = foreground_events.lock().unwrap().as_ref() {
+		if let Err(e) = events.send(Event::Interrupt) {
+			crate::print_error(line!(), e);
+		}
+	}
+}
+
+#[cfg(not(unix))]
+fn poll_process(c: &mut Child) -> Result<Option<ProcState>, String> {
+	return match c.try_wait() {
+		Ok(Some(s)) => Ok(Some(ProcState::Exited(s.code().unwrap_or(1)))),
+		Ok(None) => Ok(None),
+		Err(e) => Err(format!("Error getting status of process: {}", e)),
+	};
+}
+
+#[cfg(unix)]
+pub fn signal(job: &mut Job, sig: i32) -> Result<(), String> {
+	let r = if is_interactive() {
+		unsafe { libc::kill(-(job.pgid as libc::pid_t), sig) }
+	} else {
+		let mut r = 0;
+		for p in job.processes.iter() {
+			if !matches!(p.state, ProcState::Exited(_)) {
+				r = unsafe { libc::kill(p.child.id() as libc::pid_t, sig) };
+			}
+		}
+		r
+	};
+	if r < 0 {
+		return Err(std::io::Error::last_os_error().to_string());
+	}
+	return Ok(());
+}
+
+#[cfg(not(unix))]
+pub fn signal(job: &mut Job, _: i32) -> Result<(), String> {
+	for p in job.processes.iter_mut() {
+		if let Err(e) = p.child.kill() {
+			return Err(e.to_string());
+		}
+	}
+	return Ok(());
+}
+
+// Passes on a Ctrl-C the shell itself received. With job control the terminal
+// sends it to the job's own process group instead, so the shell only sees it
+// while a builtin runs in the foreground and forwards it as SIGINT. Without job
+// control the children share the shell's process group and already got the
+// SIGINT, so only repeated presses escalate to SIGTERM and then SIGKILL
+#[cfg(unix)]
+pub fn interrupt(job: &mut Job, presses: u32) -> Result<(), String> {
+	let sig = match presses {
+		_ if is_interactive() => libc::SIGINT,
+		0 | 1 => return Ok(()),
+		2 => libc::SIGTERM,
+		_ => libc::SIGKILL,
+	};
+	return signal_job(job, sig);
+}
+
+#[cfg(not(unix))]
+pub fn interrupt(job: &mut Job, presses: u32) -> Result<(), String> {
+	if presses < 2 {
+		return Ok(());
+	}
+	return signal(job, SIGKILL);
+}
+
+#[cfg(unix)]
+pub fn signal_pid(pid: u32, sig: i32) -> Result<(), String> {
+	if unsafe { libc::kill(pid as libc::pid_t, sig) } < 0 {
+		return Err(std::io::Error::last_os_error().to_string());
+	}
+	return Ok(());
+}
+
+#[cfg(not(unix))]
+pub fn signal_pid(_: u32, _: i32) -> Result<(), String> {
+	return Err("Sending signals to processes is not supported on this platform".to_string());
+}
+
+#[cfg(unix)]
+pub fn parse_signal(s: &str) -> Result<i32, String> {
+	if let Ok(o) = s.parse::<i32>() {
+		return Ok(o);
+	}
+	let name = s.to_uppercase();
+	let sig = match name.strip_prefix("SIG").unwrap_or(&name) {
+		"HUP" => libc::SIGHUP,
+		"INT" => libc::SIGINT,
+		"QUIT" => libc::SIGQUIT,
+		"KILL" => libc::SIGKILL,
+		"USR1" => libc::SIGUSR1,
+		"USR2" => libc::SIGUSR2,
+		"TERM" => libc::SIGTERM,
+		"CONT" => libc::SIGCONT,
+		"STOP" => libc::SIGSTOP,
+		"TSTP" => libc::SIGTSTP,
+		_ => return Err(format!("{} is not a valid signal", s)),
+	};
+	return Ok(sig);
+}
+
+#[cfg(not(unix))]
+pub fn parse_signal(s: &str) -> Result<i32, String> {
+	return match s.to_uppercase().as_str() {
+		"9" | "KILL" | "SIGKILL" | "15" | "TERM" | "SIGTERM" => Ok(SIGKILL),
+		_ => Err(format!("{} is not a valid signal", s)),
+	};
+}
+
+// Continues a stopped job without giving it the terminal
+#[cfg(unix)]
+pub fn resume(job: &mut Job) -> Result<(), String> {
+	// Stops collected while the job sat in the table are over once it continues
+	{
+		let mut r = reaped.lock().unwrap();
+		for p in job.processes.iter() {
+			if r.get(&p.child.id()).is_some_and(|x| !matches!(x, ProcState::Exited(_))) {
+				r.remove(&p.child.id());
+			}
+		}
+	}
+	signal(job, libc::SIGCONT)?;
+	for p in job.processes.iter_mut() {
+		if p.state == ProcState::Stopped {
+			p.state = ProcState::Running;
+		}
+	}
+	return Ok(());
+}
+
+#[cfg(not(unix))]
+pub fn resume(_: &mut Job) -> Result<(), String> {
+	return Ok(());
+}
+
+// Sends a signal to a job, waking it up first if it is stopped so it can act on it
+#[cfg(unix)]
+pub fn signal_job(job: &mut Job, sig: i32) -> Result<(), String> {
+	signal(job, sig)?;
+	if job.state() == JobState::Stopped && sig != libc::SIGSTOP && sig != libc::SIGTSTP {
+		resume(job)?;
+	}
+	return Ok(());
+}
+
+#[cfg(not(unix))]
+pub fn signal_job(job: &mut Job, sig: i32) -> Result<(), String> {
+	return signal(job, sig);
+}
+
+#[cfg(unix)]
+pub fn give_terminal(job: &Job) {
+	if !is_interactive() {
+		return;
+	}
+	unsafe {
+		libc::tcsetpgrp(libc::STDIN_FILENO, job.pgid as libc::pid_t);
+		if let Some(tmodes) = job.tmodes {
+			libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &tmodes);
+		}
+	}
+}
+
+#[cfg(not(unix))]
+pub fn give_terminal(_: &Job) {}
+
+// Takes the terminal back from a foreground job, remembering its modes in
+// case it was stopped and is resumed later
+#[cfg(unix)]
+pub fn take_terminal(job: &mut Job) {
+	if !is_interactive() {
+		return;
+	}
+	unsafe {
+		libc::tcsetpgrp(libc::STDIN_FILENO, *shell_pgid.lock().unwrap() as libc::pid_t);
+		let mut tmodes: libc::termios = std::mem::zeroed();
+		if libc::tcgetattr(libc::STDIN_FILENO, &mut tmodes) == 0 {
+			job.tmodes = Some(tmodes);
+		}
+		if let Some(tmodes) = *shell_tmodes.lock().unwrap() {
+			libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &tmodes);
+		}
+	}
+}
+
+#[cfg(not(unix))]
+pub fn take_terminal(_: &mut Job) {}
+
+#[cfg(unix)]
+fn stopped_status() -> i32 {
+	return 128 + libc::SIGTSTP;
+}
+
+#[cfg(not(unix))]
+fn stopped_status() -> i32 {
+	return 1;
+}
+
+// Adds a job to the table, giving it the lowest free job number
+pub fn add(mut job: Job) -> usize {
+	let mut t = table.lock().unwrap();
+	let mut id = 1;
+	while t.iter().any(|x| x.id == id) {
+		id += 1;
+	}
+	job.id = id;
+	t.push(job);
+	return id;
+}
+
+pub fn take(id: usize) -> Option<Job> {
+	let mut t = table.lock().unwrap();
+	let i = t.iter().position(|x| x.id == id)?;
+	return Some(t.remove(i));
+}
+
+// The job `fg`, `bg` and `kill` act on when none is given
+pub fn current() -> Option<usize> {
+	return table.lock().unwrap().last().map(|x| x.id);
+}
+
+pub fn parse_spec(spec: &str) -> Result<usize, String> {
+	if spec == "%" || spec == "%%" || spec == "%+" {
+		return current().ok_or("No current job".to_string());
+	}
+	let id = match spec.strip_prefix('%').unwrap_or(spec).parse::<usize>() {
+		Ok(o) => o,
+		Err(_) => return Err(format!("{} is not a valid job", spec)),
+	};
+	if !table.lock().unwrap().iter().any(|x| x.id == id) {
+		return Err(format!("No such job: {}", spec));
+	}
+	return Ok(id);
+}
+
+pub fn format_job(job: &Job, current: bool) -> String {
+	let state = job.state();
+	let marker = if current { "+" } else { " " };
+	if state == JobState::Running {
+		return format!("[{}]{} {:<12}{} &", job.id, marker, state.to_string(), job.command);
+	}
+	return format!("[{}]{} {:<12}{}", job.id, marker, state.to_string(), job.command);
+}
+
+// Moves a foreground job that was stopped with Ctrl-Z into the table
+pub fn suspend(mut job: Job) -> i32 {
+	take_terminal(&mut job);
+	println!();
+	let id = add(job);
+	let t = table.lock().unwrap();
+	println!("{}", format_job(t.iter().find(|x| x.id == id).unwrap(), true));
+	return stopped_status();
+}
+
+// Waits for a job that owns the terminal until every process exits or it is
+// stopped, in which case it is put back in the table
+pub fn wait_foreground(mut job: Job) -> Result<i32, String> {
+	let (sender, receiver) = std::sync::mpsc::channel::<Event>();
+	give_terminal(&job);
+	// Catch up on anything that happened while the job sat in the table
+	if let Err(e) = job.poll() {
+		take_terminal(&mut job);
+		return Err(e);
+	}
+	job.watch(&sender);
+	loop {
+		match job.state() {
+			JobState::Stopped => {
+				return Ok(suspend(job));
+			},
+			JobState::Done(code) => {
+				take_terminal(&mut job);
+				if job.interrupted() {
+					println!();
+				}
+				return Ok(code);
+			},
+			JobState::Running => (),
+		}
+		match next_event(&receiver) {
+			Some(Event::Process(pid, state)) => job.update(pid, state),
+			Some(Event::Tick) => {
+				if let Err(e) = job.poll() {
+					take_terminal(&mut job);
+					return Err(e);
+				}
+			},
+			Some(_) => (),
+			None => {
+				take_terminal(&mut job);
+				return Err("Lost track of the job".to_string());
+			}
+		}
+	}
+}
+
+// Reports background jobs that finished since the last prompt and forgets them
+pub fn notify() {
+	let mut t = table.lock().unwrap();
+	let current = t.last().map(|x| x.id);
+	let mut i = 0;
+	while i < t.len() {
+		let previous = t[i].state();
+		let state = match t[i].poll() {
+			Ok(o) => o,
+			Err(e) => {
+				crate::print_error(line!(), e);
+				JobState::Done(1)
+			}
+		};
+		if let JobState::Done(_) = state {
+			println!("{}", format_job(&t[i], Some(t[i].id) == current));
+			t.remove(i);
+			continue;
+		}
+		if state != previous {
+			println!("{}", format_job(&t[i], Some(t[i].id) == current));
+		}
+		i += 1;
+	}
+}
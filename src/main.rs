@@ -1,129 +1,384 @@
+#![allow(clippy::needless_return, clippy::unnecessary_unwrap)]
+
 use console::Term;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 use std::thread;
 use std::sync::mpsc::{self, Sender, Receiver};
 use std::process::Command as Cmd;
+use std::io::{PipeReader, Write};
 
 #[cfg(target_os = "linux")]
 use std::os::unix::fs::MetadataExt;
 
 mod commands;
+mod completion;
+mod editor;
+mod functions;
+mod highlight;
+mod history;
+mod jobs;
+mod parser;
+mod redirect;
+mod script;
+mod vi;
+
+// Errors in a script start with the file and line of the command that caused them
+fn location() -> String {
+	return match &*script::location.lock().unwrap() {
+		Some((file, start, offset)) => format!("{}:{}: ", file, start + offset),
+		None => String::new(),
+	};
+}
+
+// An error message, with the line of this source file it comes from at the
+// prompt or the script's own location in a script
+fn error_message<S: std::fmt::Display>(line_num: u32, e: S) -> String {
+	let location = location();
+	if location.is_empty() {
+		return format!("Error (line: {}): {}", line_num, e);
+	}
+	return format!("{}Error: {}", location, e);
+}
 
 fn print_error<S: std::fmt::Display>(line_num: u32, e: S) {
-	eprintln!("Error (line: {}): {}", line_num, e);
+	eprintln!("{}", error_message(line_num, e));
+}
+
+fn syntax_error_message<S: std::fmt::Display>(e: S) -> String {
+	return format!("{}{}{}", location(), console::style("Syntax Error: ").red().bright(), console::style(e).red().bright());
 }
 
 fn print_syntax_error<S: std::fmt::Display>(e: S) {
-	eprintln!("{}{}", console::style("Syntax Error: ").red().bright(), console::style(e).red().bright());
+	eprintln!("{}", syntax_error_message(e));
 }
 
-fn read_command(term: &Term, history: &Vec<String>) -> String {
+// Reads a line with readline style editing. Returns None when Ctrl-D is pressed
+// on an empty line and an empty line when Ctrl-C throws away the one being typed
+fn read_command(term: &Term, history: &[String], cmds: &[commands::Command<'static>]) -> Option<String> {
 	let mut inp: String = "".to_string();
-	let mut cursor_pos: i32 = 0;
+	// A byte index into `inp`, always at the start of a grapheme cluster
+	let mut cursor_pos: usize = 0;
 	let mut history_position: Option<i32> = None;
+	// A key that ended a history search and still has to be handled
+	let mut pending: Option<console::Key> = None;
+	// The row of the cursor counted from the prompt's, for redrawing wrapped lines
+	let mut row: usize = 0;
+	let mut suggestion: Option<String> = None;
+	// Whether the last key killed text, so the next kill is joined with it
+	let mut killed = false;
+	// Where the last Ctrl-Y or Alt-Y put its text, so Alt-Y can replace it
+	let mut yanked: Option<(usize, usize)> = None;
+	// The line being typed before browsing the history, given back by ArrowDown
+	let mut draft = String::new();
+	let mut undo = editor::Undo::new();
+	// Whether the last change is still open, so more typing is undone along with it
+	let mut grouped = false;
+	// Whether text was pasted into the line, which is confirmed before running
+	let mut pasted = false;
+	// Vi mode is turned on with `set EDITING_MODE vi`
+	let mut vi = if commands::data.lock().unwrap().get("EDITING_MODE").is_some_and(|x| x == "vi") {
+		Some(vi::Vi::new())
+	} else {
+		None
+	};
+	row = draw(term, &prefix(vi.as_ref().map(|x| x.mode)), &inp, 0, "", row, cmds);
 	loop {
-		match term.read_key() {
+		let key = match pending.take() {
+			Some(o) => Ok(o),
+			None => term.read_key(),
+		};
+		match key {
 			Ok(o) => {
-				if let console::Key::Char(c) = o {
-					if c.to_string() != "\t" {
-						inp.insert(cursor_pos as usize, c);
-						term.hide_cursor().unwrap();
-						if let Err(e) = term.write_str(&inp[cursor_pos as usize..]) {
-							print_error(line!(), e);
+				let at_end = cursor_pos == inp.len();
+				let cursor = cursor_pos;
+				let append = std::mem::take(&mut killed);
+				let last_yank = yanked.take();
+				let before = inp.clone();
+
+				// Vi normal mode handles its own keys and turns j, k, u, Ctrl-R and Enter
+				// into the keys that do the same in emacs mode
+				let mut handled = false;
+				let mut o = o;
+				if let Some(v) = vi.as_mut() {
+					let mut c = cursor;
+					if v.mode == vi::Mode::Normal {
+						match v.normal(&o, &mut inp, &mut c) {
+							vi::Action::Done => handled = true,
+							vi::Action::Accept => o = console::Key::Enter,
+							vi::Action::HistoryPrevious => o = console::Key::ArrowUp,
+							vi::Action::HistoryNext => o = console::Key::ArrowDown,
+							vi::Action::Undo => o = console::Key::Char('\x1f'),
+							vi::Action::Redo => o = console::Key::UnknownEscSeq(vec!['/']),
+							vi::Action::Unhandled => (),
+						}
+						cursor_pos = c;
+					} else if let console::Key::UnknownEscSeq(seq) = &o {
+						// Esc followed quickly by another key arrives as one sequence
+						if seq.len() == 1 {
+							pending = Some(console::Key::Char(seq[0]));
+							v.escape(&inp, &mut c);
+							cursor_pos = c;
+							handled = true;
+						}
+					} else if o == console::Key::Escape {
+						v.escape(&inp, &mut c);
+						cursor_pos = c;
+						handled = true;
+					} else if let console::Key::Char(ch) = o {
+						if !ch.is_control() {
+							v.record(ch);
 						}
-						term.move_cursor_left(inp.len() - cursor_pos as usize).unwrap();
-						term.show_cursor().unwrap();
-						term.move_cursor_right(1).unwrap();
-						cursor_pos += 1;
+					} else if o == console::Key::Backspace {
+						v.record('\x7f');
 					}
-					// if let Err(e) = term.write_str(c.to_string().as_str()) {
-					// 	print_error(line!(), e);
-					// }
+				}
+
+				// Up and Down move between the rows of a line that wraps or continues
+				// before going through the history
+				let vertical = match o {
+					console::Key::ArrowUp | console::Key::ArrowDown if !handled => {
+						let width = (term.size().1 as usize).max(1);
+						vertical_move(&prefix(vi.as_ref().map(|x| x.mode)), &inp, cursor_pos, o == console::Key::ArrowUp, width)
+					},
+					_ => None,
+				};
 
+				if handled {
+					// Already done by vi mode
+				} else if o == console::Key::Char('\x12') || o == console::Key::Char('\x13') {
+					// Ctrl-R / Ctrl-S
+					let (line, run, key) = search_history(term, history, &inp, o == console::Key::Char('\x12'), &mut row, cmds);
+					inp = line;
+					cursor_pos = inp.len();
+					history_position = None;
+					if run {
+						draw(term, &prefix(vi.as_ref().map(|x| x.mode)), &inp, inp.len(), "", row, cmds);
+						println!();
+						return Some(inp);
+					}
+					pending = key;
+				} else if o == console::Key::UnknownEscSeq(vec!['[', '2', '0']) {
+					// The start of a bracketed paste, ESC [ 2 0 0 ~, arrives in three parts
+					if let (Ok(console::Key::Char('0')), Ok(console::Key::Char('~'))) = (term.read_key(), term.read_key()) {
+						let text = read_paste(term);
+						inp.insert_str(cursor_pos, &text);
+						cursor_pos += text.len();
+						pasted = true;
+					}
+				} else if o == console::Key::Char('\x1f') || o == console::Key::Char('\x1a') {
+					// Ctrl-_ or Ctrl-Z
+					undo.undo(&mut inp, &mut cursor_pos);
+				} else if o == console::Key::UnknownEscSeq(vec!['/']) {
+					// Alt-/
+					undo.redo(&mut inp, &mut cursor_pos);
+				} else if o == console::Key::Tab {
+					let completion = completion::complete(&inp, cursor, cmds);
+					let candidates = &completion.candidates;
+					let insert = if candidates.len() == 1 && completion.variable {
+						format!("{}$", candidates[0])
+					} else if candidates.len() == 1 && !candidates[0].ends_with('/') {
+						format!("{} ", candidates[0])
+					} else {
+						completion::common_prefix(candidates)
+					};
+					if candidates.len() == 1 || insert.len() > cursor - completion.start {
+						inp.replace_range(completion.start..cursor, &insert);
+						cursor_pos = completion.start + insert.len();
+					} else if candidates.len() > 1 {
+						draw(term, &prefix(vi.as_ref().map(|x| x.mode)), &inp, inp.len(), "", row, cmds);
+						println!();
+						show_completions(term, candidates);
+						row = 0;
+					}
+				} else if o == console::Key::Char('\x04') {
+					// Ctrl-D exits on an empty line and deletes like Del otherwise
+					if inp.is_empty() {
+						println!();
+						return None;
+					}
+					let next = editor::next_grapheme(&inp, cursor);
+					inp.replace_range(cursor..next, "");
+				} else if o == console::Key::Char('\x02') || o == console::Key::ArrowLeft {
+					// Ctrl-B or ArrowLeft
+					cursor_pos = editor::previous_grapheme(&inp, cursor);
+				} else if o == console::Key::UnknownEscSeq(vec!['b']) {
+					// Alt-B
+					cursor_pos = editor::word_start(&inp, cursor);
+				} else if o == console::Key::Char('\x17') || o == console::Key::UnknownEscSeq(vec!['\x7f']) {
+					// Ctrl-W kills the whitespace separated word before the cursor, Alt-Backspace
+					// only up to the start of the word
+					let start = if o == console::Key::Char('\x17') {
+						editor::big_word_start(&inp, cursor)
+					} else {
+						editor::word_start(&inp, cursor)
+					};
+					editor::kill(&mut inp, start, cursor, append, true);
+					cursor_pos = start;
+					killed = true;
+				} else if o == console::Key::UnknownEscSeq(vec!['d']) {
+					// Alt-D
+					let end = editor::word_end(&inp, cursor);
+					editor::kill(&mut inp, cursor, end, append, false);
+					killed = true;
+				} else if o == console::Key::Char('\x0b') {
+					// Ctrl-K
+					let end = inp.len();
+					editor::kill(&mut inp, cursor, end, append, false);
+					killed = true;
+				} else if o == console::Key::Char('\x15') {
+					// Ctrl-U
+					editor::kill(&mut inp, 0, cursor, append, true);
+					cursor_pos = 0;
+					killed = true;
+				} else if o == console::Key::Char('\x19') {
+					// Ctrl-Y
+					if let Some(text) = editor::yank() {
+						inp.insert_str(cursor, &text);
+						cursor_pos = cursor + text.len();
+						yanked = Some((cursor, cursor + text.len()));
+					}
+				} else if o == console::Key::UnknownEscSeq(vec!['y']) && last_yank.is_some() {
+					// Alt-Y swaps the text just yanked for the previous kill
+					let (start, end) = last_yank.unwrap();
+					if let Some(text) = editor::yank_pop() {
+						inp.replace_range(start..end, &text);
+						cursor_pos = start + text.len();
+						yanked = Some((start, start + text.len()));
+					}
+				} else if o == console::Key::Char('\x14') {
+					// Ctrl-T
+					if let Some(pos) = editor::transpose(&mut inp, cursor) {
+						cursor_pos = pos;
+					}
+				} else if o == console::Key::Char('\x0c') {
+					// Ctrl-L
+					if let Err(e) = term.clear_screen() {
+						print_error(line!(), e);
+					}
+					row = 0;
 				} else if o == console::Key::Backspace && cursor_pos >= 1 {
-					term.hide_cursor().unwrap();
-					term.move_cursor_left(1).unwrap();
-					term.write_str(&inp[cursor_pos as usize..]).unwrap();
-					term.write_str(" ").unwrap();
-					term.move_cursor_left(inp.len()-cursor_pos as usize+1).unwrap();
-					term.show_cursor().unwrap();
-					inp.remove(cursor_pos as usize-1);
-					cursor_pos -= 1;
+					let previous = editor::previous_grapheme(&inp, cursor);
+					inp.replace_range(previous..cursor, "");
+					cursor_pos = previous;
+				} else if o == console::Key::Enter && parser::is_incomplete(&inp) {
+					// An unfinished command continues on the next line
+					inp.push('\n');
+					cursor_pos = inp.len();
+					if let Some(v) = vi.as_mut() {
+						v.mode = vi::Mode::Insert;
+					}
 				} else if o == console::Key::Enter {
-					print!("\n");
-					return inp;
-				} else if o == console::Key::ArrowLeft {
-					if cursor_pos >= 1 {
-						term.move_cursor_left(1).unwrap();
-						cursor_pos -= 1;
+					draw(term, &prefix(vi.as_ref().map(|x| x.mode)), &inp, inp.len(), "", row, cmds);
+					println!();
+					// A paste of several commands only runs once confirmed, in case it was a mistake
+					let count = parser::parse_script(&inp).map(|x| x.len()).unwrap_or(0);
+					if !pasted || count < 2 {
+						return Some(inp);
+					}
+					if let Err(e) = term.write_str(&format!("Run {} pasted commands? (y or n)", count)) {
+						print_error(line!(), e);
+					}
+					let answer = term.read_key();
+					println!();
+					if answer.ok() == Some(console::Key::Char('y')) {
+						return Some(inp);
 					}
-				} else if o == console::Key::ArrowRight {
-					if (cursor_pos as usize) < inp.len() {
-						term.move_cursor_right(1).unwrap();
-						cursor_pos += 1;
+					row = 0;
+				} else if (o == console::Key::ArrowRight || o == console::Key::Char('\x06') || o == console::Key::End) && at_end && suggestion.is_some() {
+					// Accept the whole suggestion
+					inp.push_str(&suggestion.take().unwrap());
+					cursor_pos = inp.len();
+				} else if o == console::Key::UnknownEscSeq(vec!['f']) && at_end && suggestion.is_some() {
+					// Alt-F accepts the suggestion up to the end of its next word
+					let s = suggestion.take().unwrap();
+					let word_end = s.find(|x: char| !x.is_whitespace()).map(|i| s[i..].find(char::is_whitespace).map(|j| i + j).unwrap_or(s.len())).unwrap_or(s.len());
+					inp.push_str(&s[..word_end]);
+					cursor_pos = inp.len();
+				} else if o == console::Key::UnknownEscSeq(vec!['f']) {
+					// Alt-F
+					cursor_pos = editor::word_end(&inp, cursor);
+				} else if o == console::Key::ArrowRight || o == console::Key::Char('\x06') {
+					// ArrowRight or Ctrl-F
+					cursor_pos = editor::next_grapheme(&inp, cursor);
+				} else if o == console::Key::Del {
+					let next = editor::next_grapheme(&inp, cursor);
+					inp.replace_range(cursor..next, "");
+				} else if let console::Key::Char(c) = o {
+					// Control characters without a binding are ignored
+					if !c.is_control() {
+						inp.insert(cursor_pos, c);
+						cursor_pos += c.len_utf8();
 					}
-				} else if o == console::Key::Del && (cursor_pos as usize) < inp.len() {
-					term.hide_cursor().unwrap();
-					inp.remove(cursor_pos as usize);
-					term.write_str(&inp[cursor_pos as usize..]).unwrap();
-					term.write_str(" ").unwrap();
-					term.move_cursor_left(inp.len()-cursor_pos as usize+1).unwrap();
-					term.show_cursor().unwrap();
-				} else if o == console::Key::ArrowUp && history.len() > 0 {
+				} else if let Some(p) = vertical {
+					cursor_pos = p;
+				} else if o == console::Key::ArrowUp && !history.is_empty() {
 					if history_position.is_none() {
 						history_position = Some(history.len() as i32-1);
+						draft = inp.clone();
 					} else {
 						if history_position.unwrap() != 0 {
 							history_position = Some(history_position.unwrap()-1);
 						}
 						
 					}
-					term.move_cursor_left(cursor_pos as usize).unwrap();
-					term.write_str(&history[history_position.unwrap() as usize]).unwrap();
-					if inp.len() > history[history_position.unwrap() as usize].len() {
-						term.write_str(&" ".repeat(inp.len() - history[history_position.unwrap() as usize].len())).unwrap();
-						term.move_cursor_left(inp.len() - history[history_position.unwrap() as usize].len()).unwrap();
-					}
-					cursor_pos = history[history_position.unwrap() as usize].len() as i32;
 					inp = history[history_position.unwrap() as usize].clone();
+					cursor_pos = inp.len();
 				} else if o == console::Key::ArrowDown && history_position.is_some() {
 					if history_position.unwrap() != history.len() as i32-1 {
 						history_position = Some(history_position.unwrap()+1);
+						inp = history[history_position.unwrap() as usize].clone();
+					} else {
+						// Past the newest entry is the line that was being typed
+						history_position = None;
+						inp = std::mem::take(&mut draft);
 					}
-					term.move_cursor_left(cursor_pos as usize).unwrap();
-					term.write_str(&history[history_position.unwrap() as usize]).unwrap();
-					if inp.len() > history[history_position.unwrap() as usize].len() {
-						term.write_str(&" ".repeat(inp.len() - history[history_position.unwrap() as usize].len())).unwrap();
-						term.move_cursor_left(inp.len() - history[history_position.unwrap() as usize].len()).unwrap();
-					}
-					cursor_pos = history[history_position.unwrap() as usize].len() as i32;
-					inp = history[history_position.unwrap() as usize].clone();
+					cursor_pos = inp.len();
 				} else if o == console::Key::Home {
-					term.move_cursor_left(cursor_pos as usize).unwrap();
 					cursor_pos = 0;
 				} else if o == console::Key::End {
-					term.move_cursor_right(inp.len() - cursor_pos as usize).unwrap();
-					cursor_pos = inp.len() as i32;
+					cursor_pos = inp.len();
+				}
+
+				// Every change can be undone. Typed text is undone in one go, in vi mode
+				// along with everything else done in the same insert
+				let undoing = o == console::Key::Char('\x1f') || o == console::Key::Char('\x1a') || o == console::Key::UnknownEscSeq(vec!['/']);
+				let inserting = vi.as_ref().is_some_and(|x| x.mode == vi::Mode::Insert);
+				let typing = match vi {
+					Some(_) => inserting,
+					None => matches!(o, console::Key::Char(c) if !c.is_control()),
+				};
+				if undoing {
+					grouped = false;
+				} else if inp != before {
+					if !grouped || !typing {
+						undo.save(&before, cursor);
+					}
+					grouped = typing;
+				} else if !inserting {
+					grouped = false;
+				}
+
+				let mode = vi.as_ref().map(|x| x.mode);
+				// Outside insert mode the cursor always sits on a character, even after
+				// moving through the history
+				if mode == Some(vi::Mode::Normal) && cursor_pos == inp.len() {
+					cursor_pos = editor::previous_grapheme(&inp, cursor_pos);
 				}
-				// if inp.len() > 0 {
-				// 	let mut available: bool = false;
-				// 	for i in (0..history.len()).rev() {
-				// 		if history[i].starts_with(&inp) && inp.len() < history[i].len() {
-				// 			term.hide_cursor().unwrap();
-				// 			term.move_cursor_right(inp.len()-cursor_pos as usize).unwrap();
-				// 			print!("{}", console::style(&history[i][inp.len()..]).dim().to_string().as_str());
-				// 			term.move_cursor_left(history[i].len()-cursor_pos as usize).unwrap();
-				// 			term.show_cursor().unwrap();
-				// 			available = true;
-				// 			break;
-				// 		}
-				// 	}
-				// 	if !available {
-				// 		term.clear_to_end_of_screen().unwrap();
-				// 	}
-				// } else {
-				// 	term.clear_to_end_of_screen().unwrap();
-				// }
 
+				// Suggestions are only shown while typing at the end of the line
+				suggestion = if cursor_pos == inp.len() {
+					history::suggest(&inp)
+				} else {
+					None
+				};
+				row = draw(term, &prefix(mode), &inp, cursor_pos, suggestion.as_deref().unwrap_or(""), row, cmds);
+			},
+			Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {
+				// Ctrl-C, the line is left on the screen like in readline and a new prompt starts below it
+				draw(term, &prefix(vi.as_ref().map(|x| x.mode)), &inp, inp.len(), "", row, cmds);
+				println!("^C");
+				return Some(String::new());
 			},
 			Err(e) => {
 				print_error(line!(), e);
@@ -132,7 +387,205 @@ fn read_command(term: &Term, history: &Vec<String>) -> String {
 	}
 }
 
-fn prefix(term: &Term) {
+// Shown at the start of every line after the first of a command that continues
+const CONTINUATION: &str = "> ";
+
+// Redraws the prompt, the highlighted line being edited and the dimmed
+// suggestion after it in place. `row` is the row the cursor was left on, counted
+// from the prompt's, so lines that wrap are cleared completely. Returns the
+// cursor's new row
+fn draw(term: &Term, prompt: &str, inp: &str, cursor_pos: usize, suggestion: &str, row: usize, cmds: &[commands::Command<'static>]) -> usize {
+	let width = (term.size().1 as usize).max(1);
+	let mut out = String::new();
+	if row > 0 {
+		out.push_str(&format!("\x1b[{}A", row));
+	}
+	out.push_str("\r\x1b[J");
+	out.push_str(prompt);
+	let newline = format!("\r\n{}", CONTINUATION);
+	out.push_str(&highlight::highlight(inp, cmds).replace('\n', &newline));
+	out.push_str(&console::style(suggestion.replace('\n', &newline)).dim().to_string());
+
+	let positions = layout(&console::strip_ansi_codes(prompt), &format!("{}{}", inp, suggestion), width);
+	let cursor = *positions.iter().find(|x| x.0 >= cursor_pos).unwrap();
+	let end = *positions.last().unwrap();
+	// Terminals only wrap once the next character is written, so move to the
+	// next row by hand when the text ends right at the edge
+	if end.1 > 0 && end.2 == 0 {
+		out.push_str("\r\n");
+	}
+	if end.1 > cursor.1 {
+		out.push_str(&format!("\x1b[{}A", end.1 - cursor.1));
+	}
+	out.push('\r');
+	if cursor.2 > 0 {
+		out.push_str(&format!("\x1b[{}C", cursor.2));
+	}
+	if let Err(e) = term.write_str(&out) {
+		print_error(line!(), e);
+	}
+	return cursor.1;
+}
+
+// Where each grapheme of `inp` is shown as (byte index, row, column), followed
+// by the position after the last one. Like in a terminal, a wide character that
+// does not fit at the end of a row is moved to the next one
+fn layout(prompt: &str, inp: &str, width: usize) -> Vec<(usize, usize, usize)> {
+	let mut positions: Vec<(usize, usize, usize)> = Vec::new();
+	let (mut row, mut column) = (0, 0);
+	let graphemes = prompt.graphemes(true).map(|x| (None, x)).chain(inp.grapheme_indices(true).map(|(i, x)| (Some(i), x)));
+	for (i, g) in graphemes {
+		let w = g.width();
+		if g != "\n" && column + w > width {
+			row += 1;
+			column = 0;
+		}
+		if let Some(i) = i {
+			positions.push((i, row, column));
+		}
+		if g == "\n" {
+			row += 1;
+			column = editor::width(CONTINUATION).min(width);
+		} else {
+			column += w;
+		}
+	}
+	if column == width {
+		row += 1;
+		column = 0;
+	}
+	positions.push((inp.len(), row, column));
+	return positions;
+}
+
+// Where the cursor goes when moved to the closest column of the row above or
+// below, in a line that wraps or continues. None when there is no such row
+fn vertical_move(prompt: &str, inp: &str, cursor_pos: usize, up: bool, width: usize) -> Option<usize> {
+	let positions = layout(&console::strip_ansi_codes(prompt), inp, width);
+	let (_, row, column) = *positions.iter().find(|x| x.0 >= cursor_pos)?;
+	let target = if up { row.checked_sub(1)? } else { row + 1 };
+	let on_row: Vec<&(usize, usize, usize)> = positions.iter().filter(|x| x.1 == target).collect();
+	let found = on_row.iter().rev().find(|x| x.2 <= column).or(on_row.first())?;
+	return Some(found.0);
+}
+
+// Reads pasted text up to the end of the bracketed paste, ESC [ 2 0 1 ~. Keys
+// in it are only text, newlines are kept for multi-line editing and tabs become
+// spaces so they are not completed
+fn read_paste(term: &Term) -> String {
+	let mut text = String::new();
+	loop {
+		match term.read_key() {
+			Ok(console::Key::UnknownEscSeq(seq)) if seq == ['[', '2', '0'] => {
+				if let (Ok(console::Key::Char('1')), Ok(console::Key::Char('~'))) = (term.read_key(), term.read_key()) {
+					break;
+				}
+			},
+			Ok(console::Key::Char(c)) if !c.is_control() => text.push(c),
+			Ok(console::Key::Enter) => text.push('\n'),
+			Ok(console::Key::Tab) => text.push(' '),
+			Ok(_) => (),
+			// A pasted Ctrl-C is read as an interruption
+			Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+			Err(_) => break,
+		}
+	}
+	return text.trim_end_matches('\n').to_string();
+}
+
+// Lists ambiguous completions in columns below the line, asking first when there are many
+fn show_completions(term: &Term, candidates: &[String]) {
+	if candidates.len() > 100 {
+		term.write_str(&format!("Display all {} possibilities? (y or n)", candidates.len())).unwrap();
+		let answer = term.read_key();
+		println!();
+		if answer.ok() != Some(console::Key::Char('y')) {
+			return;
+		}
+	}
+	let names: Vec<String> = candidates.iter().map(|x| completion::display_name(x)).collect();
+	let width = names.iter().map(|x| editor::width(x)).max().unwrap_or(0) + 2;
+	let columns = (term.size().1 as usize / width).max(1);
+	for row in names.chunks(columns) {
+		let line: String = row.iter().map(|x| format!("{}{}", x, " ".repeat(width - editor::width(x)))).collect();
+		println!("{}", line.trim_end());
+	}
+}
+
+// Finds the next history entry containing `query`, starting at `start` and
+// moving towards older entries when `reverse` is set
+fn find_in_history(history: &[String], query: &str, start: usize, reverse: bool) -> Option<usize> {
+	if reverse {
+		return (0..start.min(history.len())).rev().find(|x| history[*x].contains(query));
+	}
+	return (start..history.len()).find(|x| history[*x].contains(query));
+}
+
+// Readline style incremental search, started with Ctrl-R (older) or Ctrl-S
+// (newer). Typing narrows the search, Ctrl-R/Ctrl-S cycle through matches,
+// Enter runs the match and Ctrl-G cancels. Any other key stops searching and
+// is handed back to be handled on the matched line
+fn search_history(term: &Term, history: &[String], inp: &str, reverse: bool, row: &mut usize, cmds: &[commands::Command<'static>]) -> (String, bool, Option<console::Key>) {
+	let mut reverse = reverse;
+	let mut query = String::new();
+	// The entry currently shown, history.len() while nothing matched yet
+	let mut position = history.len();
+	let mut failed = false;
+	loop {
+		let line = if position < history.len() { history[position].as_str() } else { inp };
+		let label = match (failed, reverse) {
+			(false, true) => "(reverse-i-search)",
+			(false, false) => "(i-search)",
+			(true, true) => "(failed reverse-i-search)",
+			(true, false) => "(failed i-search)",
+		};
+		let cursor = line.find(&query).filter(|_| !query.is_empty()).unwrap_or(line.len());
+		*row = draw(term, &format!("{}`{}': ", label, query), line, cursor, "", *row, cmds);
+
+		let (start, searching) = match term.read_key() {
+			Ok(console::Key::Char('\x12')) => {
+				reverse = true;
+				(position, !query.is_empty())
+			},
+			Ok(console::Key::Char('\x13')) => {
+				reverse = false;
+				(if position < history.len() { position + 1 } else { history.len() }, !query.is_empty())
+			},
+			Ok(console::Key::Backspace) => {
+				query.pop();
+				(if reverse { history.len() } else { 0 }, true)
+			},
+			Ok(console::Key::Char(c)) if !c.is_control() => {
+				query.push(c);
+				// The current entry may still match the longer query
+				(if reverse { position.saturating_add(1) } else { position }, true)
+			},
+			Ok(console::Key::Enter) => return (line.to_string(), true, None),
+			Ok(console::Key::Escape) => return (line.to_string(), false, None),
+			// Ctrl-G or Ctrl-C gives back the line from before the search
+			Ok(console::Key::Char('\x07')) | Err(_) => return (inp.to_string(), false, None),
+			Ok(o) => return (line.to_string(), false, Some(o)),
+		};
+		if !searching {
+			continue;
+		}
+		if query.is_empty() {
+			position = history.len();
+			failed = false;
+			continue;
+		}
+		match find_in_history(history, &query, start, reverse) {
+			Some(o) => {
+				position = o;
+				failed = false;
+			},
+			None => failed = true,
+		}
+	}
+}
+
+// The prompt, starting with "(ins)" or "(cmd)" in vi mode
+fn prefix(mode: Option<vi::Mode>) -> String {
 	let current = match std::env::current_dir() {
 		Ok(o) => {
 			o.display().to_string()
@@ -143,12 +596,18 @@ fn prefix(term: &Term) {
 		}
 	};
 	let current = current.replace("\\", "/");
-	if let Err(e) = term.write_str(console::style(current).blue().bright().to_string().as_str()) {
-		print_error(line!(), e);
-	}
-	if let Err(e) = term.write_str(" > ") {
-		print_error(line!(), e);
+	let mut p = match mode {
+		Some(vi::Mode::Insert) => format!("{} ", console::style("(ins)").yellow()),
+		Some(vi::Mode::Normal) => format!("{} ", console::style("(cmd)").yellow().bright()),
+		None => String::new(),
+	};
+	p.push_str(&console::style(current).blue().bright().to_string());
+	let status = *commands::last_status.lock().unwrap();
+	if status != 0 {
+		p.push_str(&format!(" {}", console::style(format!("[{}]", status)).red().bright()));
 	}
+	p.push_str(" > ");
+	return p;
 }
 
 fn is_debug() -> bool {
@@ -196,7 +655,6 @@ fn is_executable<S: Into<String>>(s: S) -> bool {
 	// return vec!["exe", "bat", "com"].contains(&s.as_str());
 }
 
-#[cfg(target_os = "windows")]
 fn executables() -> Vec<&'static str> {
 	return vec!["exe", "bat", "com", "cmd"];
 }
@@ -280,145 +738,629 @@ fn is_valid_exe_in_current_path<S: Into<String>>(file: S) -> Option<String> {
 	return None;
 }
 
-fn run_command(inp: String, rc2: Option<&Receiver<i16>>, cmds: &Vec<commands::Command<'static>>) {
-	match shellwords::split(inp.as_str()) {
-		Ok(parsed) => {
-			if parsed.len() == 0 {
-				return;
+enum Resolved {
+	External(String),
+	Builtin(commands::Command<'static>),
+	Function,
+	// A block, if, while or for piped or redirected as a whole
+	Compound(Box<parser::Node>),
+	// Not found, which is reported on the command's own stderr once its redirections are open
+	Missing,
+}
+
+fn resolve_command(name: &str, cmds: &[commands::Command<'static>]) -> Option<Resolved> {
+	if functions::exists(name) {
+		return Some(Resolved::Function);
+	}
+	if let Some(s) = is_valid_exe_in_current_path(name) {
+		return Some(Resolved::External(s));
+	}
+	if let Some(cmd) = cmds.iter().find(|x| x.name == name) {
+		return Some(Resolved::Builtin(cmd.clone()));
+	}
+	if let Some(s) = is_valid_exe_in_path(name) {
+		return Some(Resolved::External(s));
+	}
+	return None;
+}
+
+// Replaces the name of every command that is an unquoted alias with its value.
+// The value is expanded again, but without the aliases used to get there, so
+// `alias ls='ls -F'` runs ls and aliases that use each other stop
+fn expand_aliases(inp: &str, used: &[String]) -> String {
+	let tokens = match parser::tokenize(inp) {
+		Ok(o) => o,
+		Err(_) => return inp.to_string(),
+	};
+	let mut result = String::new();
+	let mut copied: usize = 0;
+	let mut command_start = true;
+	let mut after_redirect = false;
+	for token in tokens {
+		match token {
+			parser::Token::Word(w) if inp[w.start..w.end] == w.text && parser::starts_command(&w.text, command_start && !after_redirect) => {
+				command_start = true;
+				after_redirect = false;
+			},
+			parser::Token::Word(w) => {
+				let value = if command_start && !after_redirect && !used.contains(&w.text) && inp[w.start..w.end] == w.text {
+					commands::aliases.lock().unwrap().get(&w.text).cloned()
+				} else {
+					None
+				};
+				if let Some(value) = value {
+					let mut used = used.to_vec();
+					used.push(w.text.clone());
+					result.push_str(&inp[copied..w.start]);
+					result.push_str(&expand_aliases(&value, &used));
+					copied = w.end;
+				}
+				// A redirection's file name comes before the command in "> file cmd"
+				command_start = command_start && after_redirect;
+				after_redirect = false;
+			},
+			parser::Token::Redirect(_) => after_redirect = true,
+			_ => {
+				command_start = true;
+				after_redirect = false;
+			},
+		}
+	}
+	result.push_str(&inp[copied..]);
+	return result;
+}
+
+fn expand_variables(inp: &str) -> String {
+	let status = commands::last_status.lock().unwrap().to_string();
+	let mut inp = inp.replace("$?$", &status).replace("$?", &status);
+	// The variables of a function call hide the global ones with the same name
+	for (name, value) in functions::variables() {
+		inp = inp.replace(&format!("${}$", name), &value);
+	}
+	let d = commands::data.lock().unwrap();
+	for var in d.keys() {
+		inp = inp.replace(&format!("${}$", var), &d[var]);
+	}
+	drop(d);
+	let d = commands::path.lock().unwrap();
+	if cfg!(windows) {
+		inp = inp.replace("$PATH$", &d.join(";"));
+	} else {
+		inp = inp.replace("$PATH$", &d.join(":"));
+	}
+	return inp;
+}
+
+// A builtin, function, compound statement or missing command's error running on
+// its own thread as part of a pipeline
+struct Builtin {
+	index: usize,
+	handle: thread::JoinHandle<i32>,
+	sender: Sender<i16>,
+}
+
+// Expands aliases and reads a line or script chunk into statements
+fn parse_command(inp: &str) -> Result<Vec<parser::Statement>, String> {
+	return parser::parse_script(&expand_aliases(inp, &[]));
+}
+
+// Runs a line or script chunk and returns the status of the last command that ran
+fn run_command(inp: String, cmds: &[commands::Command<'static>]) -> i32 {
+	let statements = match parse_command(&inp) {
+		Ok(o) => {
+			o
+		},
+		Err(e) => {
+			print_syntax_error(e);
+			*commands::last_status.lock().unwrap() = 2;
+			return 2;
+		}
+	};
+	return run_parsed(&statements, cmds, &redirect::Streams::inherit());
+}
+
+fn run_parsed(statements: &[parser::Statement], cmds: &[commands::Command<'static>], streams: &redirect::Streams) -> i32 {
+	let mut status = 0;
+	// break and continue only parse inside a loop, so the flow left over does not matter
+	run_statements(statements, cmds, streams, &mut status);
+	return status;
+}
+
+// What the statement that just ran asks of the statements after it
+enum Flow {
+	Next,
+	Break,
+	Continue,
+	Interrupted,
+}
+
+// Runs the statements whose connector allows it, leaving the status of the last one in `status`
+fn run_statements(statements: &[parser::Statement], cmds: &[commands::Command<'static>], streams: &redirect::Streams, status: &mut i32) -> Flow {
+	for statement in statements {
+		let run = match statement.connector {
+			parser::Connector::Always => true,
+			parser::Connector::And => *status == 0,
+			parser::Connector::Or => *status != 0,
+		};
+		if !run {
+			continue;
+		}
+		let flow = match &statement.node {
+			parser::Node::Command { source, background, line } => {
+				script::set_line(*line);
+				let (s, interrupted) = run_pipeline(source, *background, cmds, streams);
+				*status = s;
+				if interrupted { Flow::Interrupted } else { Flow::Next }
+			},
+			parser::Node::Pipeline { stages, source, line } => {
+				script::set_line(*line);
+				let (s, interrupted) = run_compound_pipeline(stages, source, cmds, streams);
+				*status = s;
+				if interrupted { Flow::Interrupted } else { Flow::Next }
+			},
+			parser::Node::Block(body) => run_statements(body, cmds, streams, status),
+			parser::Node::If { condition, then, otherwise } => {
+				match run_statements(condition, cmds, streams, status) {
+					Flow::Next if *status == 0 => run_statements(then, cmds, streams, status),
+					Flow::Next => match otherwise {
+						Some(o) => run_statements(o, cmds, streams, status),
+						None => {
+							*status = 0;
+							Flow::Next
+						}
+					},
+					flow => flow,
+				}
+			},
+			parser::Node::While { condition, body } => run_while(condition, body, cmds, streams, status),
+			parser::Node::For { variable, words, body } => run_for(variable, words, body, cmds, streams, status),
+			parser::Node::Function { name, params, body } => {
+				debug(format!("defined function {}", name));
+				functions::define(name.clone(), params.clone(), body.clone());
+				*status = 0;
+				Flow::Next
+			},
+			parser::Node::Break => Flow::Break,
+			parser::Node::Continue => Flow::Continue,
+		};
+		*commands::last_status.lock().unwrap() = *status;
+		if !matches!(flow, Flow::Next) {
+			return flow;
+		}
+	}
+	return Flow::Next;
+}
+
+// The status of a loop is the one its body ended with, or 0 if it never ran
+fn run_while(condition: &[parser::Statement], body: &[parser::Statement], cmds: &[commands::Command<'static>], streams: &redirect::Streams, status: &mut i32) -> Flow {
+	let mut last = 0;
+	loop {
+		match run_statements(condition, cmds, streams, status) {
+			Flow::Next => (),
+			flow => return flow,
+		}
+		if *status != 0 {
+			break;
+		}
+		let flow = run_statements(body, cmds, streams, status);
+		last = *status;
+		match flow {
+			Flow::Break => break,
+			Flow::Interrupted => return flow,
+			_ => (),
+		}
+	}
+	*status = last;
+	return Flow::Next;
+}
+
+// Sets `variable` to each of the words in turn, after expanding them, and runs the body
+fn run_for(variable: &str, words: &str, body: &[parser::Statement], cmds: &[commands::Command<'static>], streams: &redirect::Streams, status: &mut i32) -> Flow {
+	let found = match parser::words(words) {
+		Ok(o) => o,
+		Err(e) => {
+			print_syntax_error(e);
+			*status = 2;
+			return Flow::Next;
+		}
+	};
+	let mut values: Vec<String> = Vec::new();
+	for word in found {
+		values.extend(expand_glob(&words[word.start..word.end], expand_variables(&word.text)));
+	}
+	*status = 0;
+	for value in values {
+		commands::data.lock().unwrap().insert(variable.to_string(), value);
+		match run_statements(body, cmds, streams, status) {
+			Flow::Break => break,
+			Flow::Interrupted => return Flow::Interrupted,
+			_ => (),
+		}
+	}
+	return Flow::Next;
+}
+
+// The paths matching a word with *, ? or [ in it, sorted. Quoted or escaped
+// words and patterns matching nothing are kept as they are
+fn expand_glob(raw: &str, text: String) -> Vec<String> {
+	if raw.contains(['\'', '"', '\\']) || !text.contains(['*', '?', '[']) {
+		return vec![text];
+	}
+	let paths = match glob::glob(&text) {
+		Ok(o) => o,
+		Err(_) => return vec![text],
+	};
+	let matched: Vec<String> = paths.filter_map(|x| x.ok()).map(|x| x.to_string_lossy().to_string()).collect();
+	if matched.is_empty() {
+		return vec![text];
+	}
+	return matched;
+}
+
+// Replaces the variables in a command that was already parsed, so their values
+// stay in the words they were in and are never read as operators
+fn expand_command(command: parser::SimpleCommand) -> parser::SimpleCommand {
+	return parser::SimpleCommand {
+		args: command.args.iter().map(|x| expand_variables(x)).collect(),
+		args_string: expand_variables(&command.args_string),
+		redirects: command.redirects.iter().map(expand_redirect).collect(),
+	};
+}
+
+fn expand_redirect(redirect: &parser::Redirect) -> parser::Redirect {
+	return match redirect {
+		parser::Redirect::Stdout(target, append) => parser::Redirect::Stdout(expand_variables(target), *append),
+		parser::Redirect::Stdin(target) => parser::Redirect::Stdin(expand_variables(target)),
+		parser::Redirect::Stderr(target, append) => parser::Redirect::Stderr(expand_variables(target), *append),
+		parser::Redirect::StderrToStdout => parser::Redirect::StderrToStdout,
+	};
+}
+
+// Returns the status of the pipeline's last command and whether Ctrl-C interrupted it
+fn run_pipeline(inp: &str, background: bool, cmds: &[commands::Command<'static>], streams: &redirect::Streams) -> (i32, bool) {
+	let pipeline = match parser::parse(inp) {
+		Ok(o) => {
+			o
+		},
+		Err(e) => {
+			print_syntax_error(e);
+			return (2, false);
+		}
+	};
+	if pipeline.commands.is_empty() {
+		return (0, false);
+	}
+
+	let stages: Vec<(parser::SimpleCommand, Resolved)> = pipeline.commands.into_iter().map(expand_command).map(|x| {
+		let r = resolve_command(&x.args[0], cmds).unwrap_or(Resolved::Missing);
+		return (x, r);
+	}).collect();
+	return run_stages(inp, stages, background, cmds, streams);
+}
+
+// Runs a pipeline with blocks or loops among its stages, or a single one with redirections
+fn run_compound_pipeline(stages: &[parser::Stage], source: &str, cmds: &[commands::Command<'static>], streams: &redirect::Streams) -> (i32, bool) {
+	let mut resolved: Vec<(parser::SimpleCommand, Resolved)> = Vec::new();
+	for stage in stages {
+		match stage {
+			parser::Stage::Commands(text) => {
+				let pipeline = match parser::parse(text) {
+					Ok(o) => o,
+					Err(e) => {
+						print_syntax_error(e);
+						return (2, false);
+					}
+				};
+				for command in pipeline.commands.into_iter().map(expand_command) {
+					let r = resolve_command(&command.args[0], cmds).unwrap_or(Resolved::Missing);
+					resolved.push((command, r));
+				}
+			},
+			parser::Stage::Compound(node, redirects) => {
+				let redirects = redirects.iter().map(expand_redirect).collect();
+				let command = parser::SimpleCommand { args: Vec::new(), args_string: String::new(), redirects };
+				resolved.push((command, Resolved::Compound(node.clone())));
+			},
+		}
+	}
+	return run_stages(source, resolved, false, cmds, streams);
+}
+
+// Starts every stage of a pipeline, connected by pipes, and waits for it unless it runs in the background
+fn run_stages(inp: &str, stages: Vec<(parser::SimpleCommand, Resolved)>, background: bool, cmds: &[commands::Command<'static>], streams: &redirect::Streams) -> (i32, bool) {
+	if stages.is_empty() {
+		return (0, false);
+	}
+	if let Some((command, _)) = stages.iter().find(|x| background && matches!(x.1, Resolved::Missing)) {
+		print_syntax_error(format!("{} does not exist as a command or executable", command.args[0]));
+		return (127, false);
+	}
+	if background && stages.iter().any(|x| !matches!(x.1, Resolved::External(_))) {
+		print_error(line!(), "Builtin commands and functions cannot be run in the background");
+		return (1, false);
+	}
+
+	let mut opened: Vec<Vec<redirect::Opened>> = Vec::new();
+	for (command, _) in stages.iter() {
+		match redirect::open(&command.redirects) {
+			Ok(o) => opened.push(o),
+			Err(e) => {
+				print_error(line!(), e);
+				return (1, false);
+			}
+		}
+	}
+
+	debug("starting pipeline");
+	let start_time = std::time::Instant::now();
+	let mut job = jobs::Job::new(inp.trim().to_string());
+	let (events, receiver): (Sender<jobs::Event>, Receiver<jobs::Event>) = mpsc::channel();
+	let mut builtins: Vec<Builtin> = Vec::new();
+	let mut spawned_last = false;
+	let mut previous: Option<PipeReader> = None;
+	let count = stages.len();
+	// Taken before anything starts, so the pipelines of a function running
+	// here get Ctrl-C while they run and hand it back after
+	let outer = jobs::set_foreground(Some(events.clone()));
+
+	for (i, ((command, r), opened)) in stages.into_iter().zip(opened).enumerate() {
+		let defaults = match (streams.stdin.try_clone(), streams.stdout.try_clone(), streams.stderr.try_clone()) {
+			(Ok(stdin), Ok(stdout), Ok(stderr)) => (stdin, stdout, stderr),
+			(Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => {
+				print_error(line!(), e);
+				break;
+			}
+		};
+		let (mut stdin, mut stdout, mut stderr) = defaults;
+		if let Some(o) = previous.take() {
+			stdin = redirect::Input::Pipe(o);
+		}
+		if i + 1 < count {
+			match std::io::pipe() {
+				Ok((reader, writer)) => {
+					previous = Some(reader);
+					stdout = redirect::Output::Pipe(writer);
+				},
+				Err(e) => {
+					print_error(line!(), format!("Could not create pipe: {}", e));
+					break;
+				}
 			}
-			let parsed_clone = parsed.clone();
-			if let Some(s) = is_valid_exe_in_current_path(parsed[0].clone()) {
+		}
+		if let Err(e) = redirect::apply(opened, &mut stdin, &mut stdout, &mut stderr) {
+			print_error(line!(), e);
+			break;
+		}
+
+		match r {
+			Resolved::External(s) => {
 				debug("creating command thread");
-				let start_time = std::time::Instant::now();
-				let mut c = match Cmd::new(s).args(&parsed[1..]).spawn() {
+				let mut c = Cmd::new(s);
+				c.args(&command.args[1..]);
+				c.stdin(stdin.into_stdio());
+				c.stdout(stdout.into_stdio());
+				c.stderr(stderr.into_stdio());
+				jobs::prepare_child(&mut c, job.pgid, !background);
+				match c.spawn() {
 					Ok(o) => {
-						o
+						job.push(o);
 					},
 					Err(e) => {
 						println!("Error: {}", e);
-						return;
+						break;
 					}
+				}
+			},
+			Resolved::Builtin(cmd) => {
+				debug("starting command thread");
+				let (sc, rc): (Sender<i16>, Receiver<i16>) = mpsc::channel();
+				// Builtins never read their input, so a piped or redirected stdin is simply closed here
+				drop(stdin);
+				let io = commands::Io {
+					stdout: stdout.into_writer(),
+					exit_code: None,
 				};
-				let mut showed_error = false;
-				loop {
-					match c.try_wait() {
-						Ok(Some(_)) => {
-							break;
-						},
-						Ok(None) => (),
-						Err(e) => {
-							if !showed_error {
-								print_error(line!(), format!("Error getting status of process: {}", e));
-							}
-							showed_error = true;
-						}
+				let stderr = stderr.into_writer();
+				let events = events.clone();
+				// So set can change the variables of a function call it runs in
+				let scope = functions::scope();
+				let handle = thread::spawn(move || -> i32 {
+					functions::enter(scope);
+					// A builtin that panics fails like any other instead of leaving the pipeline waiting for it
+					let code = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| run_builtin(cmd, command, rc, io, stderr))).unwrap_or(1);
+					// Nobody is listening anymore if the pipeline was stopped with Ctrl-Z
+					let _ = events.send(jobs::Event::Builtin(i));
+					return code;
+				});
+				builtins.push(Builtin { index: i, handle, sender: sc });
+			}
+			Resolved::Function => {
+				debug("starting function thread");
+				let cmds = cmds.to_vec();
+				builtins.push(spawn_statements(i, redirect::Streams { stdin, stdout, stderr }, &events, move |streams| {
+					return functions::call(&command.args, &cmds, streams).unwrap_or_else(|e| {
+						print_error(line!(), e);
+						return 1;
+					});
+				}));
+			},
+			Resolved::Missing => {
+				drop(stdin);
+				drop(stdout);
+				let mut stderr = stderr.into_writer();
+				let message = syntax_error_message(format!("{} does not exist as a command or executable", command.args[0]));
+				let (sc, rc): (Sender<i16>, Receiver<i16>) = mpsc::channel();
+				let events = events.clone();
+				let handle = thread::spawn(move || -> i32 {
+					let _interrupts = rc;
+					if let Err(e) = writeln!(stderr, "{}", message) {
+						print_error(line!(), e);
 					}
-					if rc2.is_some() {
-						if let Ok(msg) = rc2.unwrap().try_recv() {
-							if msg == 1 {
-								break;
-							}
+					let _ = events.send(jobs::Event::Builtin(i));
+					return 127;
+				});
+				builtins.push(Builtin { index: i, handle, sender: sc });
+			},
+			Resolved::Compound(node) => {
+				debug("starting compound statement thread");
+				let cmds = cmds.to_vec();
+				builtins.push(spawn_statements(i, redirect::Streams { stdin, stdout, stderr }, &events, move |streams| {
+					let mut status = 0;
+					run_statements(&[parser::Statement { connector: parser::Connector::Always, node: *node }], &cmds, streams, &mut status);
+					return status;
+				}));
+			},
+		}
+		spawned_last = i == count - 1;
+	}
+	drop(previous);
+
+	if background {
+		jobs::set_foreground(outer);
+		if job.processes.is_empty() {
+			return (1, false);
+		}
+		let pgid = job.pgid;
+		let id = jobs::add(job);
+		println!("[{}] {}", id, pgid);
+		return (0, false);
+	}
+
+	let (status, interrupted) = wait_pipeline(job, builtins, count - 1, events, receiver);
+	jobs::set_foreground(outer);
+	debug(format!("command took {} seconds to complete", start_time.elapsed().as_secs_f32()));
+	if !spawned_last {
+		return (1, interrupted);
+	}
+	return (status, interrupted);
+}
+
+// Runs a function or compound statement of a pipeline on its own thread, with
+// the pipeline's streams in place of the shell's
+fn spawn_statements(index: usize, streams: redirect::Streams, events: &Sender<jobs::Event>, f: impl FnOnce(&redirect::Streams) -> i32 + Send + 'static) -> Builtin {
+	// Interrupts reach the commands inside on their own
+	let (sender, rc): (Sender<i16>, Receiver<i16>) = mpsc::channel();
+	let events = events.clone();
+	let scope = functions::scope();
+	let handle = thread::spawn(move || -> i32 {
+		let _interrupts = rc;
+		functions::enter(scope);
+		let code = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| f(&streams))).unwrap_or(1);
+		let _ = events.send(jobs::Event::Builtin(index));
+		return code;
+	});
+	return Builtin { index, handle, sender };
+}
+
+fn run_builtin(cmd: commands::Command<'static>, command: parser::SimpleCommand, rc: Receiver<i16>, mut io: commands::Io, mut stderr: Box<dyn Write + Send>) -> i32 {
+	let mut result = (cmd.func)(command.args, command.args_string, Some(rc), &mut io);
+	if let Err(e) = io.stdout.flush() {
+		if result.is_ok() {
+			result = Err(e.to_string());
+		}
+	}
+	if let Err(e) = &result {
+		if let Err(e2) = writeln!(stderr, "{}", error_message(line!(), e)) {
+			print_error(line!(), e2);
+		}
+		return 1;
+	}
+	return io.exit_code.unwrap_or(0);
+}
+
+// Waits until every command of a foreground pipeline is done, sleeping until
+// a builtin finishes, a process changes state or Ctrl-C is pressed. If its
+// processes get stopped with Ctrl-Z they are moved to the job table and any
+// builtins still running are left to finish on their own
+fn wait_pipeline(mut job: jobs::Job, mut builtins: Vec<Builtin>, last: usize, events: Sender<jobs::Event>, receiver: Receiver<jobs::Event>) -> (i32, bool) {
+	let last_is_builtin = builtins.iter().any(|x| x.index == last);
+	let mut status = 0;
+	let mut interrupted = false;
+	let mut presses: u32 = 0;
+	let has_processes = !job.processes.is_empty();
+	if has_processes {
+		jobs::give_terminal(&job);
+		job.watch(&events);
+	}
+	let result = loop {
+		let state = if has_processes {
+			job.state()
+		} else {
+			jobs::JobState::Done(0)
+		};
+		match state {
+			jobs::JobState::Stopped => {
+				break (jobs::suspend(job), interrupted);
+			},
+			jobs::JobState::Done(code) => {
+				if builtins.is_empty() {
+					if has_processes {
+						jobs::take_terminal(&mut job);
+						if !last_is_builtin {
+							status = code;
+						}
+						// A child killed by Ctrl-C stops the rest of the command line, like
+						// other shells do. The terminal only echoed "^C", so end that line too
+						if job.interrupted() {
+							println!();
+							interrupted = true;
 						}
 					}
-
+					break (status, interrupted);
 				}
-				c.kill().unwrap();
-				debug(format!("command took {} seconds to complete", start_time.elapsed().as_secs_f32()));
-			} else {
-				let mut found = false;
-				for cmd in cmds {
-					if cmd.name == parsed.clone()[0] {
-						found = true;
-						let (sc, rc): (Sender<i16>, Receiver<i16>) = mpsc::channel();
-						debug("starting command thread");
-						let start_time = std::time::Instant::now();
-						let cmd = cmd.clone();
-
-						let current_command = if rc2.is_some() {
-							thread::spawn(move || -> Result<(), String> {
-								return (cmd.func)(parsed, inp[inp.len().min(cmd.name.len()+1)..].to_string(), Some(rc));
-							})
-						} else {
-							thread::spawn(move || -> Result<(), String> {
-								return (cmd.func)(parsed, inp[inp.len().min(cmd.name.len()+1)..].to_string(), None);
-							})
-						};
-
-						while !current_command.is_finished() {
-							if rc2.is_some() {
-								if let Ok(msg) = rc2.unwrap().try_recv() {
-									if msg == 1 {
-										if let Err(e) = sc.send(1) {
-											print_error(line!(), e);
-										}
-									}
-								}
-							}
+			},
+			jobs::JobState::Running => (),
+		}
 
-						}
-						if let Err(e) = current_command.join().unwrap() {
-							print_error(line!(), e);
-						};
-						debug(format!("command took {} seconds to complete", start_time.elapsed().as_secs_f32()));
-						break;
-						// if let Err(e) = (cmd.func)(parsed.clone(), inp.clone()[inp.len().min(cmd.name.len()+1)..].to_string()) {
-						// 	print_error(line!(), e);
-						// };
+		match jobs::next_event(&receiver) {
+			Some(jobs::Event::Interrupt) => {
+				interrupted = true;
+				presses += 1;
+				if has_processes {
+					if let Err(e) = jobs::interrupt(&mut job, presses) {
+						print_error(line!(), e);
 					}
 				}
-				if !found {
-					if let Some(s) = is_valid_exe_in_path(parsed_clone[0].clone()) {
-						debug("creating command thread");
-						let start_time = std::time::Instant::now();
-						let mut c = match Cmd::new(s).args(&parsed_clone[1..]).spawn() {
-							Ok(o) => {
-								o
-							},
-							Err(e) => {
-								println!("Error: {}", e);
-								return;
-							}
-						};
-						let mut showed_error = false;
-						loop {
-							match c.try_wait() {
-								Ok(Some(_)) => {
-									break;
-								},
-								Ok(None) => (),
-								Err(e) => {
-									if !showed_error {
-										print_error(line!(), format!("Error getting status of process: {}", e));
-									}
-									showed_error = true;
-								}
-							}
-							if rc2.is_some() {
-								if let Ok(msg) = rc2.unwrap().try_recv() {
-									if msg == 1 {
-										break;
-									}
-								}
-							}
-						}
-						c.kill().unwrap();
-						debug(format!("command took {} seconds to complete", start_time.elapsed().as_secs_f32()));
-					} else {
-						print_syntax_error(format!("{} does not exist as a command or executable", parsed_clone[0]));
+				for b in builtins.iter() {
+					if let Err(e) = b.sender.send(1) {
+						print_error(line!(), e);
+					}
+				}
+			},
+			Some(jobs::Event::Builtin(index)) => {
+				if let Some(i) = builtins.iter().position(|x| x.index == index) {
+					let b = builtins.remove(i);
+					let code = b.handle.join().unwrap_or(1);
+					if b.index == last {
+						status = code;
 					}
 				}
+			},
+			Some(jobs::Event::Process(pid, state)) => {
+				job.update(pid, state);
+			},
+			Some(jobs::Event::Tick) => {
+				if has_processes {
+					if let Err(e) = job.poll() {
+						print_error(line!(), e);
+					}
+				}
+			},
+			None => {
+				break (1, interrupted);
 			}
-		},
-		Err(_) => {
-			print_syntax_error("Mismatched quotes");
 		}
-	}
+	};
+	return result;
 }
 
 fn main() {
 	debug("init terminal");
+	// `terminal script` and `terminal -c command` run the commands without a
+	// prompt and exit with the status of the last one
+	let args: Vec<String> = std::env::args().skip(1).filter(|x| x != "--debug").collect();
 	let term = Term::stdout();
-	if !is_debug() {
+	if !is_debug() && args.is_empty() {
 		if let Err(e) = term.clear_screen() {
 			print_error(line!(), e);
 		}
@@ -426,51 +1368,271 @@ fn main() {
 	
 	debug("init commands");
 	let cmds = commands::create_commands();
-	debug("init ctrl-c handler thread channel");
-	let (sc2, rc2): (Sender<i16>, Receiver<i16>) = mpsc::channel();
 	debug("init ctrl-c handler thread");
-	if let Err(e) = ctrlc::set_handler(move || {
-		if let Err(e2) = sc2.send(1) {
-			print_error(line!(), e2);
-		}
-	}) {
+	if let Err(e) = ctrlc::set_handler(jobs::interrupt_foreground) {
 		print_error(line!(), e);
 	}
 
 	update_path();
 
-	debug("init history vector");
-	let mut history: Vec<String> = Vec::with_capacity(100);
-	loop {
-		prefix(&term);
-		let mut inp = read_command(&term, &history).trim().to_string();
-		if inp.chars().any(|x| x.to_string() != " ") {
-			if history.len() > 0 {
-				if history.last().unwrap() != &inp {
-					if history.len() == history.capacity() {
-						history.remove(0);
-					}
-					history.push(inp.clone());
+	if !args.is_empty() {
+		let status = match args[0].as_str() {
+			"-c" => match args.get(1) {
+				Some(o) => script::run(o, "-c", &cmds),
+				None => {
+					print_error(line!(), "-c needs a command");
+					2
 				}
-			} else {
-				if history.len() == history.capacity() {
-					history.remove(0);
+			},
+			o if o.starts_with('-') => {
+				print_error(line!(), format!("Unknown option {}. Usage: terminal [--debug] [-c command | script]", o));
+				2
+			},
+			o => match script::run_file(o, &cmds) {
+				Ok(status) => status,
+				Err(e) => {
+					print_error(line!(), e);
+					127
 				}
-				history.push(inp.clone());
-			}
+			},
+		};
+		std::process::exit(status);
+	}
+
+	debug("init job control");
+	jobs::init();
+
+	debug("init completions");
+	completion::load();
+
+	// The rc file can set HISTSIZE, so it runs before the history is read
+	debug("init rc file");
+	script::load_rc(&cmds);
+
+	debug("init history");
+	history::load();
+	loop {
+		jobs::notify();
+		// Bracketed paste is only on while a line is read, programs run by the
+		// shell do not expect pasted text to come between markers
+		if let Err(e) = term.write_str("\x1b[?2004h") {
+			print_error(line!(), e);
 		}
-		let d = commands::data.lock().unwrap();
-		for var in d.keys() {
-			inp = inp.replace(&format!("${}$", var), &d[var]);
+		let inp = read_command(&term, &history::lines(), &cmds);
+		if let Err(e) = term.write_str("\x1b[?2004l") {
+			print_error(line!(), e);
 		}
-		let d = commands::path.lock().unwrap();
-		if cfg!(windows) {
-			inp = inp.replace("$PATH$", &d.join(";"));
-		} else {
-			inp = inp.replace("$PATH$", &d.join(":"));
+		let inp = match inp {
+			Some(o) => o.trim().to_string(),
+			None => std::process::exit(*commands::last_status.lock().unwrap()),
+		};
+		// Like other shells, show what `!!` and friends expanded to before running it
+		let inp = match history::expand(&inp) {
+			Ok(o) => {
+				if o != inp {
+					println!("{}", o);
+				}
+				o
+			},
+			Err(e) => {
+				print_syntax_error(e);
+				*commands::last_status.lock().unwrap() = 1;
+				continue;
+			}
+		};
+		if inp.chars().any(|x| x.to_string() != " ") {
+			history::add(&inp);
 		}
-		drop(d);
-		run_command(inp, Some(&rc2), &cmds);
+		run_command(inp, &cmds);
 	}
 	
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// Runs commands with the builtins only and returns the status of the last one
+	fn run(inp: &str) -> i32 {
+		return run_command(inp.to_string(), &commands::create_commands());
+	}
+
+	fn variable(name: &str) -> Option<String> {
+		return commands::data.lock().unwrap().get(name).cloned();
+	}
+
+	#[test]
+	fn variables_are_not_parsed() {
+		run("set t_redirect 'a > t_redirected'; set t_copy $t_redirect$");
+		assert_eq!(variable("t_copy").unwrap(), "a > t_redirected");
+		assert!(!std::path::Path::new("t_redirected").exists());
+		run("set t_pipe 'a | b'; set t_copy2 $t_pipe$");
+		assert_eq!(variable("t_copy2").unwrap(), "a | b");
+	}
+
+	#[test]
+	fn blocks() {
+		assert_eq!(run("{ set t_block a; set t_block $t_block$b }"), 0);
+		assert_eq!(variable("t_block").unwrap(), "ab");
+		assert_eq!(run("cd /nonexistent && { set t_skipped 1 }"), 1);
+		assert!(variable("t_skipped").is_none());
+	}
+
+	#[test]
+	fn loops() {
+		run("set t_for x; for x in a b c { set t_for $t_for$$x$ }");
+		assert_eq!(variable("t_for").unwrap(), "xabc");
+		run("set t_break x; for x in a b c { set t_break $t_break$$x$; break }");
+		assert_eq!(variable("t_break").unwrap(), "xa");
+		run("for x in a b { set t_continue $x$; continue; set t_continue never }");
+		assert_eq!(variable("t_continue").unwrap(), "b");
+		run("set t_nested x; for x in a b { for y in c d { set t_nested $t_nested$$x$$y$; break } }");
+		assert_eq!(variable("t_nested").unwrap(), "xacbc");
+		assert_eq!(run("set t_while 0; while set t_while 1 { break }"), 0);
+		assert_eq!(variable("t_while").unwrap(), "1");
+	}
+
+	#[test]
+	fn for_globs() {
+		let dir = std::env::temp_dir().join(format!("terminal-for-{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		std::fs::write(dir.join("my file.txt"), "").unwrap();
+		std::fs::write(dir.join("other.log"), "").unwrap();
+		// set only takes exactly two arguments, so the name has to stay one word
+		run(&format!("for f in {}/*.txt {{ set t_glob $f$ }}", dir.display()));
+		assert_eq!(variable("t_glob").unwrap(), format!("{}/my file.txt", dir.display()));
+		run(&format!("for f in '{}/*.txt' {{ set t_quoted $f$ }}", dir.display()));
+		assert_eq!(variable("t_quoted").unwrap(), format!("{}/*.txt", dir.display()));
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn conditions() {
+		run("if cd /nonexistent { set t_if then } else if set t_if2 1 { set t_if else-if } else { set t_if else }");
+		assert_eq!(variable("t_if").unwrap(), "else-if");
+		assert_eq!(run("if cd /nonexistent { set t_if3 1 }"), 0);
+		assert!(variable("t_if3").is_none());
+	}
+
+	#[test]
+	fn functions_in_a_pipeline() {
+		run("fn t_each(c) { set t_each_$c$ $c$$1$ }; t_each p | t_each q | t_each r");
+		assert_eq!(variable("t_each_p").unwrap(), "pp");
+		assert_eq!(variable("t_each_q").unwrap(), "qq");
+		assert_eq!(variable("t_each_r").unwrap(), "rr");
+		assert!(functions::scope().is_none());
+	}
+
+	#[test]
+	fn missing_command_redirected() {
+		let path = std::env::temp_dir().join(format!("terminal-missing-{}", std::process::id()));
+		assert_eq!(run(&format!("t_no_such_command 2> '{}'", path.display())), 127);
+		assert!(std::fs::read_to_string(&path).unwrap().contains("t_no_such_command does not exist"));
+		std::fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn redirected_blocks() {
+		let path = std::env::temp_dir().join(format!("terminal-blocks-{}", std::process::id()));
+		let file = path.to_string_lossy();
+		assert_eq!(run(&format!("{{ echo a; echo b }} > '{}'", file)), 0);
+		assert_eq!(std::fs::read_to_string(&path).unwrap(), "a\nb\n");
+		std::fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn stderr_to_stdout() {
+		let path = std::env::temp_dir().join(format!("terminal-test-{}", std::process::id()));
+		let file = path.to_string_lossy();
+		assert_eq!(run(&format!("cd /nonexistent > '{}' 2>&1", file)), 1);
+		let out = std::fs::read_to_string(&path).unwrap();
+		assert!(out.contains("Directory does not exist"));
+		std::fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn and_or() {
+		assert_eq!(run("set t_and 0 && set t_and 1 || set t_and 2"), 0);
+		assert_eq!(variable("t_and").unwrap(), "1");
+		// There is no such directory, so cd fails
+		assert_eq!(run("cd /nonexistent && set t_or 0 || set t_or 1"), 0);
+		assert_eq!(variable("t_or").unwrap(), "1");
+		assert_eq!(run("cd /nonexistent || cd /nonexistent"), 1);
+	}
+
+	#[test]
+	fn history_search() {
+		let history = ["ls", "cd a", "ls -l", "echo"].map(String::from);
+		assert_eq!(find_in_history(&history, "ls", history.len(), true), Some(2));
+		assert_eq!(find_in_history(&history, "ls", 2, true), Some(0));
+		assert_eq!(find_in_history(&history, "ls", 0, true), None);
+		assert_eq!(find_in_history(&history, "ls", 1, false), Some(2));
+		assert_eq!(find_in_history(&history, "cd", 2, false), None);
+		assert_eq!(find_in_history(&history, "", 9, true), Some(3));
+	}
+
+	#[test]
+	fn wide_characters_wrap() {
+		// "日" is two columns wide, so the second one does not fit on the first row
+		let positions = layout("$ ", "a日日", 5);
+		assert_eq!(positions, [(0, 0, 2), (1, 0, 3), (4, 1, 0), (7, 1, 2)]);
+		// A full last row puts the cursor at the start of the next one
+		assert_eq!(layout("", "abcd", 4).last(), Some(&(4, 1, 0)));
+		let positions = layout("> ", "e\u{301}x", 80);
+		assert_eq!(positions, [(0, 0, 2), (3, 0, 3), (4, 0, 4)]);
+	}
+
+	#[test]
+	fn moving_across_rows() {
+		let inp = "echo a\necho bc";
+		// From the end of the second row to the same column of the first
+		assert_eq!(vertical_move("$ ", inp, inp.len(), true, 80), Some(6));
+		assert_eq!(vertical_move("$ ", inp, 9, true, 80), Some(2));
+		assert_eq!(vertical_move("$ ", inp, 1, false, 80), Some(8));
+		assert_eq!(vertical_move("$ ", inp, 1, true, 80), None);
+		assert_eq!(vertical_move("$ ", inp, inp.len(), false, 80), None);
+		// Rows of a line that wraps
+		assert_eq!(vertical_move("$ ", "abcdefgh", 7, true, 5), Some(2));
+		assert_eq!(vertical_move("\x1b[1m$\x1b[0m ", "abcdefgh", 1, false, 5), Some(6));
+	}
+
+	#[test]
+	fn scripts() {
+		let cmds = commands::create_commands();
+		assert_eq!(script::run("#!/bin/terminal\n# set up\nset t_script a\n\nset t_script \\\n b\n", "test", &cmds), 0);
+		assert_eq!(variable("t_script").unwrap(), "b");
+		// The status is the last command's, not the first failure's
+		assert_eq!(script::run("cd /nonexistent\nset t_script c", "test", &cmds), 0);
+		assert_eq!(script::run("set t_script d\ncd /nonexistent", "test", &cmds), 1);
+		// A syntax error stops the script
+		assert_eq!(script::run("set t_script e\necho a |\nset t_script f", "test", &cmds), 2);
+		assert_eq!(variable("t_script").unwrap(), "e");
+		assert_eq!(script::run("if a {\n", "test", &cmds), 2);
+	}
+
+	#[test]
+	fn aliases() {
+		assert_eq!(run("alias t_ls='t_ls -F' t_la='t_ls -a' t_x=t_y t_y=t_x 't_set=set t_alias'"), 0);
+		assert_eq!(expand_aliases("t_la /tmp", &[]), "t_ls -F -a /tmp");
+		assert_eq!(expand_aliases("t_x", &[]), "t_x");
+		assert_eq!(expand_aliases("echo t_la | t_la && > f t_la", &[]), "echo t_la | t_ls -F -a && > f t_ls -F -a");
+		assert_eq!(expand_aliases("'t_la' t_la \\t_la", &[]), "'t_la' t_la \\t_la");
+		assert_eq!(expand_aliases("if t_la { t_la }", &[]), "if t_ls -F -a { t_ls -F -a }");
+		assert_eq!(run("t_set 1"), 0);
+		assert_eq!(variable("t_alias").unwrap(), "1");
+		assert_eq!(run("alias 'a b=c'"), 1);
+		assert_eq!(run("unalias t_ls t_la t_x t_y t_set"), 0);
+		assert_eq!(run("unalias t_ls"), 1);
+		assert_eq!(expand_aliases("t_la", &[]), "t_la");
+	}
+
+	#[test]
+	fn functions() {
+		run("fn t_fn(a) { set t_fn_arg $a$$#$ }; t_fn z");
+		assert_eq!(variable("t_fn_arg").unwrap(), "z1");
+		assert!(variable("a").is_none());
+		run("fn t_change(b) { set b changed; set t_changed $b$ }; t_change x");
+		assert_eq!(variable("t_changed").unwrap(), "changed");
+		assert!(variable("b").is_none());
+	}
+}
@@ -0,0 +1,1048 @@
+#[derive(Clone, Debug, PartialEq)]
+pub struct Word {
+	pub text: String,
+	pub start: usize,
+	pub end: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RedirectOp {
+	Out,
+	Append,
+	In,
+	Err,
+	ErrAppend,
+	ErrToOut,
+}
+
+impl std::fmt::Display for RedirectOp {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let s = match self {
+			RedirectOp::Out => ">",
+			RedirectOp::Append => ">>",
+			RedirectOp::In => "<",
+			RedirectOp::Err => "2>",
+			RedirectOp::ErrAppend => "2>>",
+			RedirectOp::ErrToOut => "2>&1",
+		};
+		return write!(f, "{}", s);
+	}
+}
+
+// How a list item depends on the status of the one before it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Connector {
+	Always,
+	And,
+	Or,
+}
+
+impl std::fmt::Display for Connector {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let s = match self {
+			Connector::Always => ";",
+			Connector::And => "&&",
+			Connector::Or => "||",
+		};
+		return write!(f, "{}", s);
+	}
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Token {
+	Word(Word),
+	Pipe(usize),
+	Redirect(RedirectOp),
+	Separator(Connector, usize),
+	Background(usize),
+}
+
+#[derive(Clone, Debug)]
+pub enum Redirect {
+	Stdout(String, bool),
+	Stdin(String),
+	Stderr(String, bool),
+	StderrToStdout,
+}
+
+#[derive(Clone, Debug)]
+pub struct SimpleCommand {
+	pub args: Vec<String>,
+	pub args_string: String,
+	pub redirects: Vec<Redirect>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Pipeline {
+	pub commands: Vec<SimpleCommand>,
+}
+
+// A statement of the command language. Commands are kept as text, so their
+// variables are expanded right before they run, e.g. on every pass of a loop
+#[derive(Clone, Debug)]
+pub enum Node {
+	Command {
+		source: String,
+		background: bool,
+		// Which line of the parsed text it starts on, counting from 0
+		line: usize,
+	},
+	Block(Vec<Statement>),
+	If {
+		condition: Vec<Statement>,
+		then: Vec<Statement>,
+		otherwise: Option<Vec<Statement>>,
+	},
+	While {
+		condition: Vec<Statement>,
+		body: Vec<Statement>,
+	},
+	For {
+		variable: String,
+		words: String,
+		body: Vec<Statement>,
+	},
+	// A pipeline with a block, if, while or for in it, or one of those redirected
+	Pipeline {
+		stages: Vec<Stage>,
+		// The whole pipeline, to show in the job table
+		source: String,
+		line: usize,
+	},
+	Function {
+		name: String,
+		params: Vec<String>,
+		body: Vec<Statement>,
+	},
+	Break,
+	Continue,
+}
+
+#[derive(Clone, Debug)]
+pub enum Stage {
+	// Simple commands, kept as text like Node::Command
+	Commands(String),
+	// Redirect targets are expanded when it runs
+	Compound(Box<Node>, Vec<Redirect>),
+}
+
+// A node with how it depends on the status of the statement before it
+#[derive(Clone, Debug)]
+pub struct Statement {
+	pub connector: Connector,
+	pub node: Node,
+}
+
+// Whether a line has to be continued before it can run: it ends with a
+// backslash, has an unclosed quote or opens more blocks and '(' than it closes.
+// Comments are skipped, so quotes and braces in them do not count
+pub fn is_incomplete(inp: &str) -> bool {
+	let mut chars = inp.chars().peekable();
+	let mut depth: i32 = 0;
+	// The unquoted word being read, None once a quote or backslash is in it
+	let mut word: Option<String> = Some(String::new());
+	let mut command_position = true;
+	// After if, while, for, else or a function's name the next '{' opens its block
+	let mut header = false;
+	loop {
+		let c = chars.next();
+		if c.is_none_or(|x| x.is_whitespace() || ";|&".contains(x)) {
+			match word.as_deref() {
+				Some("") => (),
+				// Braces only group commands as words of their own where a block
+				// can start, unlike in "a{b}" or "echo {"
+				Some("{") if command_position || header => {
+					depth += 1;
+					header = false;
+					command_position = true;
+				},
+				Some("}") if depth > 0 => {
+					depth -= 1;
+					command_position = true;
+				},
+				Some(w) if command_position && ["if", "while", "else"].contains(&w) => header = true,
+				Some(w) if command_position && (["fn", "for"].contains(&w) || w.ends_with("()")) => {
+					header = true;
+					command_position = false;
+				},
+				_ => command_position = false,
+			}
+			word = Some(String::new());
+			match c {
+				None => break,
+				Some(x) if x != ' ' && x != '\t' => command_position = true,
+				_ => (),
+			}
+			continue;
+		}
+		match c {
+			Some('\'') => loop {
+				match chars.next() {
+					Some('\'') => break,
+					Some(_) => (),
+					None => return true,
+				}
+			},
+			Some('"') => loop {
+				match chars.next() {
+					Some('"') => break,
+					Some('\\') => {
+						chars.next();
+					},
+					Some(_) => (),
+					None => return true,
+				}
+			},
+			Some('\\') if chars.next().is_none() => return true,
+			Some('#') if word.as_deref() == Some("") => {
+				while chars.next_if(|x| *x != '\n').is_some() {}
+				continue;
+			},
+			Some('(') => depth += 1,
+			Some(')') => depth -= 1,
+			_ => (),
+		}
+		match (&mut word, c) {
+			(Some(w), Some(x)) if !"'\"\\".contains(x) => w.push(x),
+			_ => word = None,
+		}
+	}
+	return depth > 0;
+}
+
+// The characters that end a word and start an operator when unquoted
+pub fn is_operator(c: char) -> bool {
+	return "|;&<>".contains(c);
+}
+
+// The words of a line, leaving out its operators
+pub fn words(inp: &str) -> Result<Vec<Word>, String> {
+	return Ok(tokenize(inp)?.into_iter().filter_map(|x| match x {
+		Token::Word(w) => Some(w),
+		_ => None,
+	}).collect());
+}
+
+pub fn tokenize(inp: &str) -> Result<Vec<Token>, String> {
+	let mut tokens: Vec<Token> = Vec::new();
+	let mut chars = inp.char_indices().peekable();
+
+	let mut current = String::new();
+	let mut in_word = false;
+	let mut quoted = false;
+	let mut start: usize = 0;
+
+	while let Some((i, c)) = chars.next() {
+		if !in_word {
+			start = i;
+		}
+		match c {
+			'\'' => {
+				in_word = true;
+				quoted = true;
+				loop {
+					match chars.next() {
+						Some((_, '\'')) => break,
+						Some((_, c)) => current.push(c),
+						None => return Err("Mismatched quotes".to_string()),
+					}
+				}
+			},
+			'"' => {
+				in_word = true;
+				quoted = true;
+				loop {
+					match chars.next() {
+						Some((_, '"')) => break,
+						Some((_, '\\')) => {
+							match chars.peek() {
+								// A backslash before a newline joins the two lines
+								Some((_, '\n')) => {
+									chars.next();
+								},
+								Some((_, n)) if ['$', '`', '"', '\\'].contains(n) => {
+									current.push(*n);
+									chars.next();
+								},
+								_ => current.push('\\'),
+							}
+						},
+						Some((_, c)) => current.push(c),
+						None => return Err("Mismatched quotes".to_string()),
+					}
+				}
+			},
+			'\\' if chars.peek().is_some_and(|x| x.1 == '\n') => {
+				chars.next();
+			},
+			'\\' => {
+				in_word = true;
+				quoted = true;
+				match chars.next() {
+					Some((_, n)) => current.push(n),
+					None => current.push('\\'),
+				}
+			},
+			'|' | ';' | '\n' => {
+				if in_word {
+					tokens.push(Token::Word(Word { text: std::mem::take(&mut current), start, end: i }));
+					in_word = false;
+					quoted = false;
+				}
+				if c == ';' || c == '\n' {
+					tokens.push(Token::Separator(Connector::Always, i));
+				} else if chars.next_if(|x| x.1 == '|').is_some() {
+					tokens.push(Token::Separator(Connector::Or, i));
+				} else {
+					tokens.push(Token::Pipe(i));
+				}
+			},
+			'&' if inp[i..].starts_with("&&") => {
+				if in_word {
+					tokens.push(Token::Word(Word { text: std::mem::take(&mut current), start, end: i }));
+					in_word = false;
+					quoted = false;
+				}
+				chars.next();
+				tokens.push(Token::Separator(Connector::And, i));
+			},
+			'&' => {
+				if in_word {
+					tokens.push(Token::Word(Word { text: std::mem::take(&mut current), start, end: i }));
+					in_word = false;
+					quoted = false;
+				}
+				tokens.push(Token::Background(i));
+			},
+			'>' => {
+				// A bare "2" right before '>' names stderr instead of being an argument
+				let fd2 = in_word && !quoted && current == "2";
+				if fd2 {
+					current.clear();
+				} else if in_word {
+					tokens.push(Token::Word(Word { text: std::mem::take(&mut current), start, end: i }));
+				}
+				in_word = false;
+				quoted = false;
+
+				let append = chars.next_if(|x| x.1 == '>').is_some();
+				if fd2 && !append && inp[i + 1..].starts_with("&1") {
+					chars.next();
+					chars.next();
+					tokens.push(Token::Redirect(RedirectOp::ErrToOut));
+				} else {
+					tokens.push(Token::Redirect(match (fd2, append) {
+						(false, false) => RedirectOp::Out,
+						(false, true) => RedirectOp::Append,
+						(true, false) => RedirectOp::Err,
+						(true, true) => RedirectOp::ErrAppend,
+					}));
+				}
+			},
+			'<' => {
+				if in_word {
+					tokens.push(Token::Word(Word { text: std::mem::take(&mut current), start, end: i }));
+					in_word = false;
+					quoted = false;
+				}
+				tokens.push(Token::Redirect(RedirectOp::In));
+			},
+			// A comment runs to the end of the line, but only from the start of a word as in "a #b"
+			'#' if !in_word => {
+				while chars.next_if(|x| x.1 != '\n').is_some() {}
+			},
+			c if c.is_whitespace() => {
+				if in_word {
+					tokens.push(Token::Word(Word { text: std::mem::take(&mut current), start, end: i }));
+					in_word = false;
+					quoted = false;
+				}
+			},
+			c => {
+				in_word = true;
+				current.push(c);
+			}
+		}
+	}
+	if in_word {
+		tokens.push(Token::Word(Word { text: current, start, end: inp.len() }));
+	}
+
+	return Ok(tokens);
+}
+
+// Removes the backslash-newline pairs that continue a line, except inside single quotes
+fn join_lines(inp: &str) -> String {
+	let mut result = String::new();
+	let mut chars = inp.chars().peekable();
+	let mut single = false;
+	let mut double = false;
+	while let Some(c) = chars.next() {
+		match c {
+			'\'' if !double => single = !single,
+			'"' if !single => double = !double,
+			'\\' if !single => {
+				match chars.next() {
+					Some('\n') => (),
+					Some(n) => {
+						result.push(c);
+						result.push(n);
+					},
+					None => result.push(c),
+				}
+				continue;
+			},
+			_ => (),
+		}
+		result.push(c);
+	}
+	return result;
+}
+
+// `words` holds the command's arguments along with whether a redirection was
+// removed right before each one, so args_string never contains the redirections
+fn simple_command(inp: &str, words: Vec<(Word, bool)>, redirects: Vec<Redirect>) -> SimpleCommand {
+	let mut args_string = String::new();
+	for i in 1..words.len() {
+		if i > 1 {
+			if words[i].1 {
+				args_string.push(' ');
+			} else {
+				args_string.push_str(&inp[words[i - 1].0.end..words[i].0.start]);
+			}
+		}
+		args_string.push_str(&inp[words[i].0.start..words[i].0.end]);
+	}
+	return SimpleCommand {
+		args: words.into_iter().map(|x| x.0.text).collect(),
+		args_string: join_lines(&args_string),
+		redirects,
+	};
+}
+
+fn redirect(op: RedirectOp, target: String) -> Redirect {
+	return match op {
+		RedirectOp::Out => Redirect::Stdout(target, false),
+		RedirectOp::Append => Redirect::Stdout(target, true),
+		RedirectOp::In => Redirect::Stdin(target),
+		RedirectOp::Err => Redirect::Stderr(target, false),
+		RedirectOp::ErrAppend => Redirect::Stderr(target, true),
+		RedirectOp::ErrToOut => Redirect::StderrToStdout,
+	};
+}
+
+pub fn parse(inp: &str) -> Result<Pipeline, String> {
+	let tokens = tokenize(inp)?;
+	let mut commands: Vec<SimpleCommand> = Vec::new();
+	if tokens.is_empty() {
+		return Ok(Pipeline { commands });
+	}
+
+	let mut words: Vec<(Word, bool)> = Vec::new();
+	let mut redirects: Vec<Redirect> = Vec::new();
+	let mut after_redirect = false;
+	let mut tokens = tokens.into_iter();
+	while let Some(token) = tokens.next() {
+		match token {
+			Token::Word(w) => {
+				words.push((w, after_redirect));
+				after_redirect = false;
+			},
+			Token::Separator(c, _) => {
+				return Err(format!("Unexpected '{}'", c));
+			},
+			Token::Background(_) => {
+				return Err("Unexpected '&'".to_string());
+			},
+			Token::Pipe(_) => {
+				if words.is_empty() {
+					return Err("Expected a command before '|'".to_string());
+				}
+				commands.push(simple_command(inp, std::mem::take(&mut words), std::mem::take(&mut redirects)));
+				after_redirect = false;
+			},
+			Token::Redirect(RedirectOp::ErrToOut) => {
+				redirects.push(Redirect::StderrToStdout);
+				after_redirect = true;
+			},
+			Token::Redirect(op) => {
+				let target = match tokens.next() {
+					Some(Token::Word(w)) => w.text,
+					_ => return Err(format!("Expected a file name after '{}'", op)),
+				};
+				redirects.push(redirect(op, target));
+				after_redirect = true;
+			}
+		}
+	}
+	if words.is_empty() {
+		if redirects.is_empty() {
+			return Err("Expected a command after '|'".to_string());
+		}
+		return Err("Expected a command to redirect".to_string());
+	}
+	commands.push(simple_command(inp, words, redirects));
+
+	return Ok(Pipeline { commands });
+}
+
+// `s` in single quotes, with the quotes in it closed, escaped and reopened
+pub fn single_quote(s: &str) -> String {
+	return format!("'{}'", s.replace('\'', "'\\''"));
+}
+
+// Whether another command starts after an unquoted `word`: the braces of a
+// block anywhere, and if, while and else where a command would be
+pub fn starts_command(word: &str, command_position: bool) -> bool {
+	return word == "{" || word == "}" || (command_position && ["if", "while", "else"].contains(&word));
+}
+
+fn is_variable(s: &str) -> bool {
+	return !s.is_empty() && s.chars().all(|x| x.is_alphanumeric() || x == '_');
+}
+
+fn is_function_name(s: &str) -> bool {
+	return !s.is_empty() && s.chars().all(|x| x.is_alphanumeric() || x == '_' || x == '-');
+}
+
+struct Parser<'a> {
+	inp: &'a str,
+	tokens: Vec<Token>,
+	pos: usize,
+	// Where the text of the next command starts
+	offset: usize,
+	// How many loops the statement being read is in, for break and continue
+	loops: usize,
+}
+
+impl Parser<'_> {
+	fn peek(&self) -> Option<Token> {
+		return self.tokens.get(self.pos).cloned();
+	}
+
+	// Where the next token starts if it is `word` without quotes, which makes it a keyword
+	fn at(&self, word: &str) -> Option<usize> {
+		match self.tokens.get(self.pos) {
+			Some(Token::Word(w)) if w.text == word && self.inp[w.start..w.end] == w.text => Some(w.start),
+			_ => None,
+		}
+	}
+
+	fn advance(&mut self) -> Option<Token> {
+		let token = self.peek()?;
+		self.pos += 1;
+		self.offset = match &token {
+			Token::Word(w) => w.end,
+			Token::Pipe(i) => i + 1,
+			Token::Separator(c, i) => i + c.to_string().len(),
+			Token::Background(i) => i + 1,
+			_ => self.offset,
+		};
+		return Some(token);
+	}
+
+	// Reads statements until the tokens run out or, when `stop` is given, until
+	// that keyword starts a statement or ends a command. `stop` itself is left
+	// for the caller
+	fn list(&mut self, stop: Option<&str>) -> Result<Vec<Statement>, String> {
+		let mut statements: Vec<Statement> = Vec::new();
+		let mut connector = Connector::Always;
+		loop {
+			match self.peek() {
+				None => break,
+				// Blank lines and lines ending in an operator are not empty commands
+				Some(Token::Separator(_, i)) if self.inp[i..].starts_with('\n') => {
+					self.advance();
+					continue;
+				},
+				Some(Token::Separator(c, _)) => return Err(format!("Expected a command before '{}'", c)),
+				Some(Token::Background(_)) => return Err("Expected a command before '&'".to_string()),
+				_ => (),
+			}
+			if stop.is_some_and(|x| self.at(x).is_some()) {
+				break;
+			}
+
+			let mut node = self.pipeline(stop)?;
+			let mut next = Connector::Always;
+			match self.peek() {
+				None => (),
+				Some(Token::Separator(c, _)) => {
+					self.advance();
+					next = c;
+				},
+				Some(Token::Background(_)) => {
+					self.advance();
+					match &mut node {
+						Node::Command { background, .. } => *background = true,
+						_ => return Err("Only commands can be run in the background".to_string()),
+					}
+				},
+				Some(Token::Word(_)) if stop.is_some_and(|x| self.at(x).is_some()) => (),
+				Some(token) => {
+					let text = match token {
+						Token::Word(w) => w.text,
+						Token::Redirect(op) => op.to_string(),
+						_ => "|".to_string(),
+					};
+					return Err(format!("Expected a new line or ';' before '{}'", text));
+				},
+			}
+			statements.push(Statement { connector, node });
+			connector = next;
+		}
+		if connector != Connector::Always {
+			return Err(format!("Expected a command after '{}'", connector));
+		}
+		return Ok(statements);
+	}
+
+	// The line the next token is on, counting from 0
+	fn line(&self) -> usize {
+		let rest = &self.inp[self.offset..];
+		return self.inp[..self.inp.len() - rest.trim_start().len()].matches('\n').count();
+	}
+
+	// Whether the token at `pos` starts a block, if, while or for
+	fn compound_at(&self, pos: usize) -> bool {
+		return match self.tokens.get(pos) {
+			Some(Token::Word(w)) => self.inp[w.start..w.end] == w.text && ["{", "if", "while", "for"].contains(&w.text.as_str()),
+			_ => false,
+		};
+	}
+
+	// A statement, joined by '|' with the ones after it when a block, if, while
+	// or for is piped. Those can also be redirected as a whole
+	fn pipeline(&mut self, stop: Option<&str>) -> Result<Node, String> {
+		let line = self.line();
+		let start = self.inp.len() - self.inp[self.offset..].trim_start().len();
+		let mut stages: Vec<Stage> = Vec::new();
+		loop {
+			let node = self.statement(stop)?;
+			let stage = match node {
+				Node::Command { source, .. } => Stage::Commands(source),
+				Node::Block(_) | Node::If { .. } | Node::While { .. } | Node::For { .. } => Stage::Compound(Box::new(node), self.redirects()?),
+				_ if stages.is_empty() => return Ok(node),
+				_ => return Err("Only commands, blocks and loops can be piped".to_string()),
+			};
+			stages.push(stage);
+			if !matches!(self.peek(), Some(Token::Pipe(_))) {
+				break;
+			}
+			self.advance();
+			if matches!(self.peek(), None | Some(Token::Separator(..)) | Some(Token::Background(_))) || stop.is_some_and(|x| self.at(x).is_some()) {
+				return Err("Expected a command after '|'".to_string());
+			}
+		}
+		if stages.len() == 1 {
+			match stages.pop() {
+				Some(Stage::Commands(source)) => return Ok(Node::Command { source, background: false, line }),
+				Some(Stage::Compound(node, redirects)) if redirects.is_empty() => return Ok(*node),
+				Some(stage) => stages.push(stage),
+				None => (),
+			}
+		}
+		let source = self.inp[start..self.offset].trim().to_string();
+		return Ok(Node::Pipeline { stages, source, line });
+	}
+
+	// The redirections after a block, if, while or for
+	fn redirects(&mut self) -> Result<Vec<Redirect>, String> {
+		let mut redirects: Vec<Redirect> = Vec::new();
+		while let Some(Token::Redirect(op)) = self.peek() {
+			self.advance();
+			if op == RedirectOp::ErrToOut {
+				redirects.push(Redirect::StderrToStdout);
+				continue;
+			}
+			match self.advance() {
+				Some(Token::Word(w)) => redirects.push(redirect(op, w.text)),
+				_ => return Err(format!("Expected a file name after '{}'", op)),
+			}
+		}
+		return Ok(redirects);
+	}
+
+	fn statement(&mut self, stop: Option<&str>) -> Result<Node, String> {
+		if self.at("if").is_some() {
+			self.advance();
+			return self.if_statement();
+		}
+		if self.at("while").is_some() {
+			self.advance();
+			let condition = self.condition("while")?;
+			self.loops += 1;
+			let body = self.block();
+			self.loops -= 1;
+			return Ok(Node::While { condition, body: body? });
+		}
+		if self.at("for").is_some() {
+			self.advance();
+			return self.for_statement();
+		}
+		for (keyword, node) in [("break", Node::Break), ("continue", Node::Continue)] {
+			if self.at(keyword).is_some() {
+				if self.loops == 0 {
+					return Err(format!("'{}' can only be used in a loop", keyword));
+				}
+				self.advance();
+				return Ok(node);
+			}
+		}
+		if self.at("fn").is_some() {
+			self.advance();
+			return self.function();
+		}
+		// The POSIX `name() { ... }`
+		if let Some(Token::Word(w)) = self.peek() {
+			let posix = self.inp[w.start..w.end] == w.text && w.text.strip_suffix("()").is_some_and(is_function_name);
+			if posix && matches!(self.tokens.get(self.pos + 1), Some(Token::Word(b)) if b.text == "{") {
+				return self.function();
+			}
+		}
+		if self.at("{").is_some() {
+			return Ok(Node::Block(self.block()?));
+		}
+		if self.at("}").is_some() {
+			return Err("Unexpected '}'".to_string());
+		}
+		if self.at("else").is_some() {
+			return Err("'else' has to follow the '}' of an if on the same line".to_string());
+		}
+		return self.command(stop);
+	}
+
+	// A pipeline, up to the separator or `stop` keyword after it
+	fn command(&mut self, stop: Option<&str>) -> Result<Node, String> {
+		let start = self.offset;
+		let line = self.line();
+		let mut end = self.inp.len();
+		while let Some(token) = self.peek() {
+			match token {
+				Token::Separator(_, i) | Token::Background(i) => {
+					end = i;
+					break;
+				},
+				// The rest of the pipeline is read by Parser::pipeline
+				Token::Pipe(i) if self.compound_at(self.pos + 1) => {
+					end = i;
+					break;
+				},
+				Token::Word(w) if stop.is_some_and(|x| self.at(x).is_some()) => {
+					end = w.start;
+					break;
+				},
+				_ => {
+					self.advance();
+				},
+			}
+		}
+		let source = self.inp[start..end].to_string();
+		parse(&source)?;
+		return Ok(Node::Command { source, background: false, line });
+	}
+
+	// The statements between '{' and '}'
+	fn block(&mut self) -> Result<Vec<Statement>, String> {
+		if self.at("{").is_none() {
+			return Err("Expected '{'".to_string());
+		}
+		self.advance();
+		let body = self.list(Some("}"))?;
+		if self.at("}").is_none() {
+			return Err("Expected '}' at the end of the block".to_string());
+		}
+		self.advance();
+		return Ok(body);
+	}
+
+	// The commands between `keyword` and the '{' of its block
+	fn condition(&mut self, keyword: &str) -> Result<Vec<Statement>, String> {
+		let condition = self.list(Some("{"))?;
+		if condition.is_empty() {
+			return Err(format!("Expected a condition after '{}'", keyword));
+		}
+		if self.at("{").is_none() {
+			return Err(format!("Expected '{{' after the condition of '{}'", keyword));
+		}
+		return Ok(condition);
+	}
+
+	// `if condition { ... } else if condition { ... } else { ... }`
+	fn if_statement(&mut self) -> Result<Node, String> {
+		let condition = self.condition("if")?;
+		let then = self.block()?;
+		let mut otherwise = None;
+		if self.at("else").is_some() {
+			self.advance();
+			if self.at("if").is_some() {
+				self.advance();
+				otherwise = Some(vec![Statement { connector: Connector::Always, node: self.if_statement()? }]);
+			} else if self.at("{").is_some() {
+				otherwise = Some(self.block()?);
+			} else {
+				return Err("Expected '{' or 'if' after 'else'".to_string());
+			}
+		}
+		return Ok(Node::If { condition, then, otherwise });
+	}
+
+	// `name(a, b) { ... }`, after the `fn` keyword if there is one. The body
+	// is its own scope for break and continue
+	fn function(&mut self) -> Result<Node, String> {
+		let start = self.offset;
+		let end = loop {
+			if let Some(i) = self.at("{") {
+				break i;
+			}
+			match self.peek() {
+				Some(Token::Word(_)) => {
+					self.advance();
+				},
+				_ => return Err("Expected '{' after the function's parameters".to_string()),
+			}
+		};
+		let signature = self.inp[start..end].trim();
+		let (name, params) = match signature.strip_suffix(')').and_then(|x| x.split_once('(')) {
+			Some((name, params)) => (name.trim().to_string(), params),
+			None => return Err(format!("Expected a name and parameters like name(a, b) instead of '{}'", signature)),
+		};
+		if !is_function_name(&name) {
+			return Err(format!("{} is not a valid function name", name));
+		}
+		let params: Vec<String> = params.split(|x: char| x == ',' || x.is_whitespace()).filter(|x| !x.is_empty()).map(|x| x.to_string()).collect();
+		if let Some(p) = params.iter().find(|x| !is_variable(x)) {
+			return Err(format!("{} is not a valid parameter name", p));
+		}
+		let loops = std::mem::replace(&mut self.loops, 0);
+		let body = self.block();
+		self.loops = loops;
+		return Ok(Node::Function { name, params, body: body? });
+	}
+
+	// `for name in words { ... }`, the words are kept as text until the loop runs
+	fn for_statement(&mut self) -> Result<Node, String> {
+		let variable = match self.advance() {
+			Some(Token::Word(w)) if is_variable(&w.text) => w.text,
+			_ => return Err("Expected a variable name after 'for'".to_string()),
+		};
+		if self.at("in").is_none() {
+			return Err(format!("Expected 'in' after 'for {}'", variable));
+		}
+		self.advance();
+		let start = self.offset;
+		let end = loop {
+			if let Some(i) = self.at("{") {
+				break i;
+			}
+			match self.peek() {
+				Some(Token::Word(_)) => {
+					self.advance();
+				},
+				_ => return Err("Expected '{' after the words of 'for'".to_string()),
+			}
+		};
+		let words = self.inp[start..end].to_string();
+		self.loops += 1;
+		let body = self.block();
+		self.loops -= 1;
+		return Ok(Node::For { variable, words, body: body? });
+	}
+}
+
+// Reads a line or script into statements without expanding anything, so each
+// command can be expanded and parsed right before it runs
+pub fn parse_script(inp: &str) -> Result<Vec<Statement>, String> {
+	let mut parser = Parser { inp, tokens: tokenize(inp)?, pos: 0, offset: 0, loops: 0 };
+	return parser.list(None);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn texts(inp: &str) -> Vec<String> {
+		return words(inp).unwrap().into_iter().map(|x| x.text).collect();
+	}
+
+	#[test]
+	fn parse_blocks() {
+		let statements = parse_script("{ a; b } && { c }").unwrap();
+		assert!(matches!(&statements[0].node, Node::Block(body) if body.len() == 2));
+		assert!(matches!(&statements[1].node, Node::Block(body) if body.len() == 1));
+		assert!(parse_script("{ a").is_err());
+		assert!(parse_script("a; }").is_err());
+
+		let statements = parse_script("{ echo a; echo b } > f").unwrap();
+		match &statements[0].node {
+			Node::Pipeline { stages, source, .. } => {
+				assert_eq!(source, "{ echo a; echo b } > f");
+				assert!(matches!(&stages[..], [Stage::Compound(node, redirects)] if matches!(**node, Node::Block(_)) && redirects.len() == 1));
+			},
+			node => panic!("expected a pipeline, got {:?}", node),
+		}
+		let statements = parse_script("echo x | for y in a { cat } | sort").unwrap();
+		assert!(matches!(&statements[0].node, Node::Pipeline { stages, .. } if matches!(&stages[..], [Stage::Commands(_), Stage::Compound(..), Stage::Commands(_)])));
+	}
+
+	#[test]
+	fn parse_flow_control() {
+		let statements = parse_script("if a { b } else if c { d } else { e }").unwrap();
+		match &statements[0].node {
+			Node::If { otherwise: Some(o), .. } => assert!(matches!(o[0].node, Node::If { otherwise: Some(_), .. })),
+			node => panic!("expected an if, got {:?}", node),
+		}
+		let statements = parse_script("while a { b; continue }").unwrap();
+		assert!(matches!(&statements[0].node, Node::While { body, .. } if matches!(body[1].node, Node::Continue)));
+		let statements = parse_script("for x in a b { if c { break } }").unwrap();
+		match &statements[0].node {
+			Node::For { variable, words, .. } => {
+				assert_eq!(variable, "x");
+				assert_eq!(words.trim(), "a b");
+			},
+			node => panic!("expected a for, got {:?}", node),
+		}
+		assert!(parse_script("if a").is_err());
+		assert!(parse_script("for x a { b }").is_err());
+	}
+
+	#[test]
+	fn break_outside_loop() {
+		assert!(parse_script("break").is_err());
+		assert!(parse_script("if a { continue }").is_err());
+		assert!(parse_script("while a { fn f() { break } }").is_err());
+		assert!(parse_script("while a { { break } }").is_ok());
+	}
+
+	#[test]
+	fn incomplete_blocks() {
+		assert!(is_incomplete("if a {"));
+		assert!(is_incomplete("for x in a b {\n echo $x$"));
+		assert!(is_incomplete("{ a; { b }"));
+		assert!(is_incomplete("if a { b } else {"));
+		assert!(!is_incomplete("echo {"));
+		assert!(!is_incomplete("echo a{b"));
+		assert!(!is_incomplete("echo '{'"));
+		assert!(!is_incomplete("if a { b }"));
+		assert!(!is_incomplete("echo a # {"));
+	}
+
+	#[test]
+	fn tokenize_pipes() {
+		let tokens = tokenize("a | b|c").unwrap();
+		assert!(matches!(tokens[1], Token::Pipe(2)));
+		assert!(matches!(tokens[3], Token::Pipe(_)));
+		assert_eq!(tokens.len(), 5);
+		let pipeline = parse("ls -l | grep 'a | b'").unwrap();
+		assert_eq!(pipeline.commands.len(), 2);
+		assert_eq!(pipeline.commands[1].args, ["grep", "a | b"]);
+		assert!(parse("a | | b").is_err());
+		assert!(parse("a |").is_err());
+	}
+
+	#[test]
+	fn tokenize_quotes() {
+		assert_eq!(texts(r#"echo 'a b' "c \"d\"" e\ f"#), ["echo", "a b", "c \"d\"", "e f"]);
+		assert_eq!(texts("echo 'a|b;c' \"x&&y\""), ["echo", "a|b;c", "x&&y"]);
+		assert_eq!(texts("a'b'\"c\""), ["abc"]);
+		assert!(tokenize("echo 'a").is_err());
+		assert!(tokenize("echo \"a").is_err());
+	}
+
+	#[test]
+	fn tokenize_redirects() {
+		let tokens = tokenize("a 2>&1 > f 2>>g <h").unwrap();
+		assert_eq!(tokens[1], Token::Redirect(RedirectOp::ErrToOut));
+		assert_eq!(tokens[2], Token::Redirect(RedirectOp::Out));
+		assert_eq!(tokens[4], Token::Redirect(RedirectOp::ErrAppend));
+		assert_eq!(tokens[6], Token::Redirect(RedirectOp::In));
+		assert_eq!(texts("echo a '>' b"), ["echo", "a", ">", "b"]);
+	}
+
+	#[test]
+	fn parse_redirects() {
+		let pipeline = parse("a 2>&1 > out | b < in 2>> err").unwrap();
+		assert_eq!(pipeline.commands.len(), 2);
+		assert_eq!(pipeline.commands[0].args, ["a"]);
+		assert!(matches!(pipeline.commands[0].redirects[..], [Redirect::StderrToStdout, Redirect::Stdout(ref f, false)] if f == "out"));
+		assert_eq!(pipeline.commands[1].args, ["b"]);
+		assert!(matches!(pipeline.commands[1].redirects[..], [Redirect::Stdin(ref f), Redirect::Stderr(ref g, true)] if f == "in" && g == "err"));
+		assert!(parse("a >").is_err());
+		assert!(parse("a | | b").is_err());
+	}
+
+	#[test]
+	fn tokenize_separators() {
+		let tokens = tokenize("a && b || c; d").unwrap();
+		assert!(matches!(tokens[1], Token::Separator(Connector::And, _)));
+		assert!(matches!(tokens[3], Token::Separator(Connector::Or, _)));
+		assert!(matches!(tokens[5], Token::Separator(Connector::Always, _)));
+		assert_eq!(tokens.len(), 7);
+		assert_eq!(texts("echo 'a && b' c\\;"), ["echo", "a && b", "c;"]);
+	}
+
+	#[test]
+	fn parse_connectors() {
+		let statements = parse_script("a && b || c; d").unwrap();
+		let connectors: Vec<Connector> = statements.iter().map(|x| x.connector).collect();
+		assert_eq!(connectors, [Connector::Always, Connector::And, Connector::Or, Connector::Always]);
+		assert!(parse_script("&& a").is_err());
+		assert!(parse_script("a &&").is_err());
+	}
+
+	#[test]
+	fn parse_background() {
+		let statements = parse_script("sleep 1 & echo a").unwrap();
+		assert_eq!(statements.len(), 2);
+		assert!(matches!(statements[0].node, Node::Command { ref source, background: true, .. } if source.trim() == "sleep 1"));
+		assert!(matches!(statements[1].node, Node::Command { background: false, .. }));
+		assert!(matches!(tokenize("a &").unwrap()[1], Token::Background(2)));
+		assert!(parse_script("& a").is_err());
+		assert!(parse_script("{ a } &").is_err());
+	}
+
+	#[test]
+	fn single_quoting() {
+		for s in ["it's", "'", "a b", "$x$ | y", ""] {
+			assert_eq!(texts(&single_quote(s)), [s]);
+		}
+	}
+
+	#[test]
+	fn incomplete() {
+		assert!(is_incomplete("echo 'a"));
+		assert!(is_incomplete("echo \"a"));
+		assert!(is_incomplete("echo a \\"));
+		assert!(is_incomplete("echo a |\\"));
+		assert!(!is_incomplete("echo a \\\\"));
+		assert!(!is_incomplete("echo 'a # b'"));
+		assert!(!is_incomplete("echo a # it's"));
+		assert!(!is_incomplete(""));
+	}
+
+	#[test]
+	fn tokenize_comments() {
+		assert_eq!(texts("echo a # b c"), ["echo", "a"]);
+		assert_eq!(texts("echo a#b '#c'"), ["echo", "a#b", "#c"]);
+	}
+
+	#[test]
+	fn parse_lines() {
+		let statements = parse_script("a\n\nb; c\n  d").unwrap();
+		let lines: Vec<usize> = statements.iter().map(|x| match x.node {
+			Node::Command { line, .. } => line,
+			_ => panic!("expected a command"),
+		}).collect();
+		assert_eq!(lines, [0, 2, 2, 3]);
+	}
+
+	#[test]
+	fn parse_functions() {
+		let statements = parse_script("echo x; fn f(a b) { echo $a$ }; f() { g }").unwrap();
+		assert!(matches!(&statements[1].node, Node::Function { name, params, .. } if name == "f" && params == &["a", "b"]));
+		assert!(matches!(&statements[2].node, Node::Function { name, params, .. } if name == "f" && params.is_empty()));
+		assert!(parse_script("fn a.b() { c }").is_err());
+		assert!(is_incomplete("f() {"));
+		assert!(is_incomplete("fn f(a) {\n echo $a$"));
+	}
+}
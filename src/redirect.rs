@@ -0,0 +1,128 @@
+use std::fs::{File, OpenOptions};
+use std::io::{PipeReader, PipeWriter, Write};
+use std::process::Stdio;
+
+use crate::parser::Redirect;
+
+pub enum Input {
+	Inherit,
+	File(File),
+	Pipe(PipeReader),
+}
+
+pub enum Output {
+	Stdout,
+	Stderr,
+	File(File),
+	Pipe(PipeWriter),
+}
+
+impl Input {
+	pub fn try_clone(&self) -> Result<Input, String> {
+		match self {
+			Input::Inherit => Ok(Input::Inherit),
+			Input::File(f) => f.try_clone().map(Input::File).map_err(|e| e.to_string()),
+			Input::Pipe(p) => p.try_clone().map(Input::Pipe).map_err(|e| e.to_string()),
+		}
+	}
+
+	pub fn into_stdio(self) -> Stdio {
+		match self {
+			Input::Inherit => Stdio::inherit(),
+			Input::File(f) => f.into(),
+			Input::Pipe(p) => p.into(),
+		}
+	}
+}
+
+impl Output {
+	pub fn try_clone(&self) -> Result<Output, String> {
+		match self {
+			Output::Stdout => Ok(Output::Stdout),
+			Output::Stderr => Ok(Output::Stderr),
+			Output::File(f) => f.try_clone().map(Output::File).map_err(|e| e.to_string()),
+			Output::Pipe(p) => p.try_clone().map(Output::Pipe).map_err(|e| e.to_string()),
+		}
+	}
+
+	pub fn into_stdio(self) -> Stdio {
+		match self {
+			Output::Stdout => std::io::stdout().into(),
+			Output::Stderr => std::io::stderr().into(),
+			Output::File(f) => f.into(),
+			Output::Pipe(p) => p.into(),
+		}
+	}
+
+	pub fn into_writer(self) -> Box<dyn Write + Send> {
+		match self {
+			Output::Stdout => Box::new(std::io::stdout()),
+			Output::Stderr => Box::new(std::io::stderr()),
+			Output::File(f) => Box::new(f),
+			Output::Pipe(p) => Box::new(p),
+		}
+	}
+}
+
+// Where commands read and write when nothing else is given. A function's body
+// runs with the streams of the function, so it can be piped and redirected
+pub struct Streams {
+	pub stdin: Input,
+	pub stdout: Output,
+	pub stderr: Output,
+}
+
+impl Streams {
+	pub fn inherit() -> Streams {
+		return Streams { stdin: Input::Inherit, stdout: Output::Stdout, stderr: Output::Stderr };
+	}
+}
+
+pub enum Opened {
+	Stdin(File),
+	Stdout(File),
+	Stderr(File),
+	StderrToStdout,
+}
+
+fn open_output(file: &str, append: bool) -> Result<File, String> {
+	let f = if append {
+		OpenOptions::new().append(true).create(true).open(file)
+	} else {
+		File::create(file)
+	};
+	return f.map_err(|e| format!("Cannot write to {}: {}", file, e));
+}
+
+// Opens every file named by a command's redirections, in order, so nothing
+// is spawned if one of the targets cannot be used
+pub fn open(redirects: &[Redirect]) -> Result<Vec<Opened>, String> {
+	let mut opened: Vec<Opened> = Vec::new();
+	for r in redirects {
+		opened.push(match r {
+			Redirect::Stdin(file) => {
+				match File::open(file) {
+					Ok(o) => Opened::Stdin(o),
+					Err(e) => return Err(format!("Cannot read from {}: {}", file, e)),
+				}
+			},
+			Redirect::Stdout(file, append) => Opened::Stdout(open_output(file, *append)?),
+			Redirect::Stderr(file, append) => Opened::Stderr(open_output(file, *append)?),
+			Redirect::StderrToStdout => Opened::StderrToStdout,
+		});
+	}
+	return Ok(opened);
+}
+
+pub fn apply(opened: Vec<Opened>, stdin: &mut Input, stdout: &mut Output, stderr: &mut Output) -> Result<(), String> {
+	for o in opened {
+		match o {
+			Opened::Stdin(f) => *stdin = Input::File(f),
+			Opened::Stdout(f) => *stdout = Output::File(f),
+			Opened::Stderr(f) => *stderr = Output::File(f),
+			Opened::StderrToStdout => *stderr = stdout.try_clone()?,
+		}
+	}
+	return Ok(());
+}
+
@@ -0,0 +1,87 @@
+#![allow(non_upper_case_globals)]
+
+use std::path::PathBuf;
+use std::sync::Mutex;
+use lazy_static::lazy_static;
+
+use crate::commands::Command;
+
+lazy_static! {
+	// The file and first line of the script chunk running now, and the line of
+	// the command running now within it, so errors can point at it
+	pub static ref location: Mutex<Option<(String, usize, usize)>> = Mutex::new(None);
+}
+
+// Runs the commands in `source` one after another as if they were typed, with
+// `name` and the line number in error messages. Like at the prompt, a command
+// continues on the next line when it is unfinished. Blank lines, lines starting
+// with '#' and a "#!" first line are skipped. Returns the status of the last
+// command, or 2 when the script has a syntax error, which stops it
+pub fn run(source: &str, name: &str, cmds: &[Command<'static>]) -> i32 {
+	let previous = location.lock().unwrap().take();
+	let mut status = 0;
+	let mut chunk = String::new();
+	let mut start: usize = 0;
+	for (i, line) in source.lines().enumerate() {
+		if chunk.is_empty() {
+			if line.trim().is_empty() || line.trim_start().starts_with('#') {
+				continue;
+			}
+			start = i + 1;
+		} else {
+			chunk.push('\n');
+		}
+		chunk.push_str(line);
+		if crate::parser::is_incomplete(&chunk) {
+			continue;
+		}
+		*location.lock().unwrap() = Some((name.to_string(), start, 0));
+		let statements = match crate::parse_command(&chunk) {
+			Ok(o) => o,
+			Err(e) => {
+				crate::print_syntax_error(e);
+				*crate::commands::last_status.lock().unwrap() = 2;
+				status = 2;
+				chunk.clear();
+				break;
+			}
+		};
+		chunk.clear();
+		status = crate::run_parsed(&statements, cmds, &crate::redirect::Streams::inherit());
+	}
+	if !chunk.is_empty() {
+		*location.lock().unwrap() = Some((name.to_string(), start, 0));
+		crate::print_syntax_error("Unexpected end of file, the command is unfinished");
+		status = 2;
+	}
+	*location.lock().unwrap() = previous;
+	return status;
+}
+
+// Called before each command runs with the line it starts on, counted from
+// the start of its chunk
+pub fn set_line(offset: usize) {
+	if let Some(l) = location.lock().unwrap().as_mut() {
+		l.2 = offset;
+	}
+}
+
+pub fn run_file(path: &str, cmds: &[Command<'static>]) -> Result<i32, String> {
+	let source = std::fs::read_to_string(path).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+	return Ok(run(&source, path, cmds));
+}
+
+// Commands run before the first prompt, e.g. ~/.config/terminal/rc on Linux
+pub fn rc_path() -> Option<PathBuf> {
+	return dirs::config_dir().map(|x| x.join("terminal").join("rc"));
+}
+
+pub fn load_rc(cmds: &[Command<'static>]) {
+	let path = match rc_path() {
+		Some(o) if o.exists() => o,
+		_ => return,
+	};
+	if let Err(e) = run_file(&path.to_string_lossy(), cmds) {
+		crate::print_error(line!(), e);
+	}
+}
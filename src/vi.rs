@@ -0,0 +1,548 @@
+use console::Key;
+use unicode_segmentation::UnicodeSegmentation;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Mode {
+	Insert,
+	Normal,
+}
+
+// What read_command should do after a key was given to normal mode
+pub enum Action {
+	Done,
+	Accept,
+	HistoryPrevious,
+	HistoryNext,
+	Undo,
+	Redo,
+	// Not a vi command, so it is handled like in emacs mode
+	Unhandled,
+}
+
+#[derive(Clone, Copy)]
+enum Motion {
+	Simple(char),
+	Find(char, char),
+}
+
+enum Command {
+	Move(usize, Motion),
+	Operator(char, usize, Motion),
+	// dd, cc and yy
+	Line(char),
+	Simple(char, usize),
+	Replace(char, usize),
+}
+
+enum Parse {
+	Incomplete,
+	Invalid,
+	Complete(Command),
+}
+
+pub struct Vi {
+	pub mode: Mode,
+	// Keys of the normal mode command being typed, like "d2f"
+	pending: Vec<char>,
+	// Keys of the last change for '.', including the text typed after it in insert mode
+	last_change: Vec<char>,
+	// The change being recorded while in insert mode
+	recording: Option<Vec<char>>,
+	// The last f, F, t or T and its character, for ';' and ','
+	last_find: Option<(char, char)>,
+}
+
+// Splits a leading count off the keys; a lone '0' is a motion, not a count.
+// Counts too large for a usize become the largest one
+fn take_count(keys: &[char]) -> (Option<usize>, &[char]) {
+	let digits = keys.iter().enumerate().take_while(|(i, c)| c.is_ascii_digit() && !(*i == 0 && **c == '0')).count();
+	if digits == 0 {
+		return (None, keys);
+	}
+	let count: String = keys[..digits].iter().collect();
+	return (Some(count.parse().unwrap_or(usize::MAX)), &keys[digits..]);
+}
+
+fn parse_motion(keys: &[char]) -> Result<Option<Motion>, ()> {
+	return match keys.first() {
+		None => Ok(None),
+		Some(c) if "hlwWbBeE0^$;, ".contains(*c) => Ok(Some(Motion::Simple(*c))),
+		Some(c) if "fFtT".contains(*c) => Ok(keys.get(1).map(|x| Motion::Find(*c, *x))),
+		Some(_) => Err(()),
+	};
+}
+
+fn parse(keys: &[char]) -> Parse {
+	let (count, rest) = take_count(keys);
+	let c = match rest.first() {
+		Some(o) => *o,
+		None => return Parse::Incomplete,
+	};
+	let count = count.unwrap_or(1);
+	if "dcy".contains(c) {
+		let (count2, rest) = take_count(&rest[1..]);
+		if rest.first() == Some(&c) {
+			return Parse::Complete(Command::Line(c));
+		}
+		return match parse_motion(rest) {
+			Ok(Some(m)) => Parse::Complete(Command::Operator(c, count.saturating_mul(count2.unwrap_or(1)), m)),
+			Ok(None) => Parse::Incomplete,
+			Err(_) => Parse::Invalid,
+		};
+	}
+	if c == 'r' {
+		return match rest.get(1) {
+			Some(r) => Parse::Complete(Command::Replace(*r, count)),
+			None => Parse::Incomplete,
+		};
+	}
+	if "xXDCsSpPiaIAu.jk~".contains(c) {
+		return Parse::Complete(Command::Simple(c, count));
+	}
+	return match parse_motion(rest) {
+		Ok(Some(m)) => Parse::Complete(Command::Move(count, m)),
+		Ok(None) => Parse::Incomplete,
+		Err(_) => Parse::Invalid,
+	};
+}
+
+// 0 for whitespace, 1 for word characters and 2 for other characters. With
+// `big`, everything that is not whitespace is one class, like for W, B and E
+fn class(g: &str, big: bool) -> u8 {
+	let c = g.chars().next().unwrap_or(' ');
+	if c.is_whitespace() {
+		return 0;
+	}
+	if big || c.is_alphanumeric() || c == '_' {
+		return 1;
+	}
+	return 2;
+}
+
+fn next_word(graphemes: &[String], mut pos: usize, big: bool) -> usize {
+	if pos < graphemes.len() {
+		let start = class(&graphemes[pos], big);
+		while pos < graphemes.len() && start != 0 && class(&graphemes[pos], big) == start {
+			pos += 1;
+		}
+	}
+	while pos < graphemes.len() && class(&graphemes[pos], big) == 0 {
+		pos += 1;
+	}
+	return pos;
+}
+
+fn word_end(graphemes: &[String], mut pos: usize, big: bool) -> usize {
+	pos += 1;
+	while pos < graphemes.len() && class(&graphemes[pos], big) == 0 {
+		pos += 1;
+	}
+	if pos >= graphemes.len() {
+		return graphemes.len().saturating_sub(1);
+	}
+	let c = class(&graphemes[pos], big);
+	while pos + 1 < graphemes.len() && class(&graphemes[pos + 1], big) == c {
+		pos += 1;
+	}
+	return pos;
+}
+
+fn previous_word(graphemes: &[String], mut pos: usize, big: bool) -> usize {
+	while pos > 0 && class(&graphemes[pos - 1], big) == 0 {
+		pos -= 1;
+	}
+	if pos == 0 {
+		return 0;
+	}
+	let c = class(&graphemes[pos - 1], big);
+	while pos > 0 && class(&graphemes[pos - 1], big) == c {
+		pos -= 1;
+	}
+	return pos;
+}
+
+fn find(graphemes: &[String], pos: usize, kind: char, target: char, count: usize) -> Option<usize> {
+	let target = target.to_string();
+	let mut p = pos;
+	for _ in 0..count {
+		p = match kind {
+			'f' | 't' => (p + 1..graphemes.len()).find(|x| graphemes[*x] == target)?,
+			_ => (0..p).rev().find(|x| graphemes[*x] == target)?,
+		};
+	}
+	return Some(match kind {
+		't' => p - 1,
+		'T' => p + 1,
+		_ => p,
+	});
+}
+
+// A count never has to go past the length of the line, and a larger one typed
+// by mistake would take forever or run out of memory
+fn capped(count: usize, graphemes: &[String]) -> usize {
+	return count.min(graphemes.len().max(1));
+}
+
+fn byte_pos(graphemes: &[String], pos: usize) -> usize {
+	return graphemes[..pos.min(graphemes.len())].iter().map(|x| x.len()).sum();
+}
+
+impl Vi {
+	pub fn new() -> Vi {
+		return Vi {
+			mode: Mode::Insert,
+			pending: Vec::new(),
+			last_change: Vec::new(),
+			recording: None,
+			last_find: None,
+		};
+	}
+
+	// Remembers a key typed in insert mode as part of the change '.' repeats
+	pub fn record(&mut self, c: char) {
+		if let Some(r) = self.recording.as_mut() {
+			r.push(c);
+		}
+	}
+
+	// Leaves insert mode, moving the cursor back onto the last character typed
+	pub fn escape(&mut self, inp: &str, cursor: &mut usize) {
+		self.mode = Mode::Normal;
+		if let Some(mut r) = self.recording.take() {
+			r.push('\x1b');
+			self.last_change = r;
+		}
+		*cursor = crate::editor::previous_grapheme(inp, *cursor);
+	}
+
+	pub fn normal(&mut self, key: &Key, inp: &mut String, cursor: &mut usize) -> Action {
+		let c = match key {
+			Key::Char(c) if !c.is_control() => *c,
+			Key::Enter => return Action::Accept,
+			// Ctrl-R
+			Key::Char('\x12') => return Action::Redo,
+			Key::Escape => {
+				self.pending.clear();
+				return Action::Done;
+			},
+			Key::Backspace => 'h',
+			_ => return Action::Unhandled,
+		};
+		self.pending.push(c);
+		let command = match parse(&self.pending) {
+			Parse::Incomplete => return Action::Done,
+			Parse::Invalid => {
+				self.pending.clear();
+				return Action::Done;
+			},
+			Parse::Complete(o) => o,
+		};
+		let keys = std::mem::take(&mut self.pending);
+		let action = self.run(command, &keys, inp, cursor);
+		// Outside insert mode the cursor always sits on a character
+		if self.mode == Mode::Normal && *cursor >= inp.len() {
+			*cursor = crate::editor::previous_grapheme(inp, inp.len());
+		}
+		return action;
+	}
+
+	// Starts a change: saves the keys for '.'
+	fn change(&mut self, keys: &[char]) {
+		self.last_change = keys.to_vec();
+	}
+
+	fn insert(&mut self, keys: &[char]) {
+		self.mode = Mode::Insert;
+		self.recording = Some(keys.to_vec());
+	}
+
+	// Where a motion goes from `pos`, and whether an operator includes the character there
+	fn target(&mut self, graphemes: &[String], pos: usize, motion: Motion, count: usize) -> Option<(usize, bool)> {
+		let count = capped(count, graphemes);
+		let mut p = pos;
+		let inclusive = matches!(motion, Motion::Find(_, _) | Motion::Simple('e' | 'E' | '$' | ';' | ','));
+		let motion = match motion {
+			Motion::Find(kind, target) => {
+				self.last_find = Some((kind, target));
+				motion
+			},
+			Motion::Simple(c) if c == ';' || c == ',' => {
+				let (kind, target) = self.last_find?;
+				if c == ';' {
+					Motion::Find(kind, target)
+				} else {
+					let reversed = match kind { 'f' => 'F', 'F' => 'f', 't' => 'T', _ => 't' };
+					Motion::Find(reversed, target)
+				}
+			},
+			_ => motion,
+		};
+		match motion {
+			Motion::Simple('0') => p = 0,
+			Motion::Simple('^') => p = graphemes.iter().position(|x| class(x, false) != 0).unwrap_or(graphemes.len()),
+			Motion::Simple('$') => p = graphemes.len().saturating_sub(1),
+			Motion::Find(kind, target) => {
+				p = find(graphemes, pos, kind, target, count)?;
+			},
+			Motion::Simple(c) => {
+				for _ in 0..count {
+					let next = match c {
+						'h' => p.saturating_sub(1),
+						'l' | ' ' => (p + 1).min(graphemes.len()),
+						'w' | 'W' => next_word(graphemes, p, c == 'W'),
+						'b' | 'B' => previous_word(graphemes, p, c == 'B'),
+						_ => word_end(graphemes, p, c == 'E'),
+					};
+					// At the start or end of the line
+					if next == p {
+						break;
+					}
+					p = next;
+				}
+			},
+		}
+		return Some((p, inclusive));
+	}
+
+	fn run(&mut self, command: Command, keys: &[char], inp: &mut String, cursor: &mut usize) -> Action {
+		let mut graphemes: Vec<String> = inp.graphemes(true).map(|x| x.to_string()).collect();
+		let mut pos = inp[..*cursor].graphemes(true).count();
+		match command {
+			Command::Move(count, motion) => {
+				if let Some((p, _)) = self.target(&graphemes, pos, motion, count) {
+					pos = p;
+				}
+			},
+			Command::Operator(op, count, motion) => {
+				// Like in vi, cw changes to the end of the word instead of up to the next one
+				let motion = match motion {
+					Motion::Simple(c) if op == 'c' && (c == 'w' || c == 'W') && graphemes.get(pos).is_some_and(|x| class(x, false) != 0) => {
+						Motion::Simple(if c == 'w' { 'e' } else { 'E' })
+					},
+					_ => motion,
+				};
+				let (p, inclusive) = match self.target(&graphemes, pos, motion, count) {
+					Some(o) => o,
+					None => return Action::Done,
+				};
+				let start = pos.min(p);
+				let end = if inclusive { pos.max(p) + 1 } else { pos.max(p) }.min(graphemes.len());
+				let (from, to) = (byte_pos(&graphemes, start), byte_pos(&graphemes, end));
+				if op == 'y' {
+					crate::editor::save(&inp[from..to]);
+				} else {
+					self.change(keys);
+					crate::editor::kill(inp, from, to, false, false);
+					if op == 'c' {
+						self.insert(keys);
+					}
+				}
+				*cursor = from;
+				return Action::Done;
+			},
+			Command::Line(op) => {
+				if op == 'y' {
+					crate::editor::save(inp);
+					return Action::Done;
+				}
+				self.change(keys);
+				let end = inp.len();
+				crate::editor::kill(inp, 0, end, false, false);
+				*cursor = 0;
+				if op == 'c' {
+					self.insert(keys);
+				}
+				return Action::Done;
+			},
+			Command::Replace(r, count) => {
+				if pos.saturating_add(count) > graphemes.len() {
+					return Action::Done;
+				}
+				self.change(keys);
+				for g in graphemes[pos..pos + count].iter_mut() {
+					*g = r.to_string();
+				}
+				pos += count - 1;
+				*inp = graphemes.concat();
+			},
+			Command::Simple(c, count) => {
+				return self.simple(c, count, keys, inp, cursor);
+			},
+		}
+		*cursor = byte_pos(&graphemes, pos);
+		return Action::Done;
+	}
+
+	fn simple(&mut self, c: char, count: usize, keys: &[char], inp: &mut String, cursor: &mut usize) -> Action {
+		let graphemes: Vec<String> = inp.graphemes(true).map(|x| x.to_string()).collect();
+		let pos = inp[..*cursor].graphemes(true).count();
+		let count = capped(count, &graphemes);
+		match c {
+			'x' | 'X' | 'D' | 'C' | 's' | 'S' => {
+				// Shorthands for operators with a motion
+				let command = match c {
+					'x' => Command::Operator('d', count, Motion::Simple('l')),
+					'X' => Command::Operator('d', count, Motion::Simple('h')),
+					'D' => Command::Operator('d', 1, Motion::Simple('$')),
+					'C' => Command::Operator('c', 1, Motion::Simple('$')),
+					's' => Command::Operator('c', count, Motion::Simple('l')),
+					_ => Command::Line('c'),
+				};
+				if graphemes.is_empty() && c != 'C' && c != 'S' && c != 's' {
+					return Action::Done;
+				}
+				if graphemes.is_empty() {
+					self.change(keys);
+					self.insert(keys);
+					return Action::Done;
+				}
+				return self.run(command, keys, inp, cursor);
+			},
+			'p' | 'P' => {
+				let text = match crate::editor::yank() {
+					Some(o) => o.repeat(count),
+					None => return Action::Done,
+				};
+				self.change(keys);
+				let at = if c == 'p' && !graphemes.is_empty() { byte_pos(&graphemes, pos + 1) } else { *cursor };
+				inp.insert_str(at, &text);
+				// The cursor ends on the last character pasted
+				*cursor = at + text.len() - text.graphemes(true).next_back().map(|x| x.len()).unwrap_or(0);
+			},
+			'i' | 'a' | 'I' | 'A' => {
+				*cursor = match c {
+					'a' if !graphemes.is_empty() => byte_pos(&graphemes, pos + 1),
+					'I' => byte_pos(&graphemes, graphemes.iter().position(|x| class(x, false) != 0).unwrap_or(graphemes.len())),
+					'A' => inp.len(),
+					_ => *cursor,
+				};
+				self.insert(keys);
+			},
+			'~' => {
+				self.change(keys);
+				let end = (pos + count).min(graphemes.len());
+				let toggled: String = graphemes[pos..end].iter().map(|x| {
+					if x.chars().next().is_some_and(|c| c.is_uppercase()) {
+						x.to_lowercase()
+					} else {
+						x.to_uppercase()
+					}
+				}).collect();
+				let from = byte_pos(&graphemes, pos);
+				inp.replace_range(from..byte_pos(&graphemes, end), &toggled);
+				*cursor = from + toggled.len();
+			},
+			'u' => return Action::Undo,
+			'.' => {
+				self.repeat(inp, cursor);
+			},
+			'j' => return Action::HistoryNext,
+			'k' => return Action::HistoryPrevious,
+			_ => (),
+		}
+		return Action::Done;
+	}
+
+	// Replays the last change, including the text it inserted
+	fn repeat(&mut self, inp: &mut String, cursor: &mut usize) {
+		let keys = self.last_change.clone();
+		for c in keys.iter() {
+			if self.mode == Mode::Normal {
+				self.normal(&Key::Char(*c), inp, cursor);
+			} else if *c == '\x1b' {
+				self.escape(inp, cursor);
+			} else if *c == '\x7f' {
+				let i = crate::editor::previous_grapheme(inp, *cursor);
+				inp.replace_range(i..*cursor, "");
+				*cursor = i;
+			} else {
+				inp.insert(*cursor, *c);
+				*cursor += c.len_utf8();
+			}
+		}
+		// A change that never left insert mode is finished here
+		if self.mode == Mode::Insert {
+			self.escape(inp, cursor);
+		}
+		self.last_change = keys;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// Types `keys` in normal mode on `inp` with the cursor at `cursor`
+	fn normal(inp: &str, cursor: usize, keys: &str) -> (Vi, String, usize) {
+		let mut vi = Vi::new();
+		vi.mode = Mode::Normal;
+		let mut inp = inp.to_string();
+		let mut cursor = cursor;
+		for c in keys.chars() {
+			vi.normal(&Key::Char(c), &mut inp, &mut cursor);
+		}
+		return (vi, inp, cursor);
+	}
+
+	#[test]
+	fn counts() {
+		assert_eq!(take_count(&['1', '2', 'd', 'w']), (Some(12), &['d', 'w'][..]));
+		assert_eq!(take_count(&['0', 'w']), (None, &['0', 'w'][..]));
+		assert_eq!(take_count(&['1', '0']), (Some(10), &[][..]));
+		let huge: Vec<char> = "99999999999999999999999w".chars().collect();
+		assert_eq!(take_count(&huge), (Some(usize::MAX), &['w'][..]));
+	}
+
+	#[test]
+	fn parsing() {
+		let keys = |s: &str| s.chars().collect::<Vec<char>>();
+		assert!(matches!(parse(&keys("2d3w")), Parse::Complete(Command::Operator('d', 6, Motion::Simple('w')))));
+		assert!(matches!(parse(&keys("dd")), Parse::Complete(Command::Line('d'))));
+		assert!(matches!(parse(&keys("d")), Parse::Incomplete));
+		assert!(matches!(parse(&keys("df")), Parse::Incomplete));
+		assert!(matches!(parse(&keys("dq")), Parse::Invalid));
+		assert!(matches!(parse(&keys("3rx")), Parse::Complete(Command::Replace('x', 3))));
+		assert!(matches!(parse(&keys("tx")), Parse::Complete(Command::Move(1, Motion::Find('t', 'x')))));
+		assert!(matches!(parse(&keys("99999999999999999999d99999999999999999999w")), Parse::Complete(Command::Operator('d', usize::MAX, _))));
+	}
+
+	#[test]
+	fn motions() {
+		let line = "one two.three four";
+		assert_eq!(normal(line, 0, "w").2, 4);
+		assert_eq!(normal(line, 0, "2w").2, 7);
+		assert_eq!(normal(line, 0, "2W").2, 14);
+		assert_eq!(normal(line, 0, "e").2, 2);
+		assert_eq!(normal(line, 14, "b").2, 8);
+		assert_eq!(normal(line, 4, "$").2, 17);
+		assert_eq!(normal(line, 4, "0").2, 0);
+		assert_eq!(normal(line, 0, "fo;").2, 15);
+		assert_eq!(normal(line, 0, "tr").2, 9);
+		assert_eq!(normal("añb", 0, "ll").2, 3);
+		// Counts past the end of the line stop there
+		assert_eq!(normal(line, 0, "99999999999999999999l").2, 17);
+		assert_eq!(normal(line, 17, "99999999999999999999b").2, 0);
+	}
+
+	#[test]
+	fn changes() {
+		let _lock = crate::editor::kill_ring_test.lock().unwrap();
+		let line = "one two three";
+		assert_eq!(normal(line, 0, "dw").1, "two three");
+		assert_eq!(normal(line, 0, "2dw").1, "three");
+		assert_eq!(normal(line, 4, "d$").1, "one ");
+		assert_eq!(normal(line, 4, "dd").1, "");
+		assert_eq!(normal(line, 0, "3x").1, " two three");
+		assert_eq!(normal(line, 0, "dw.").1, "three");
+		assert_eq!(normal(line, 0, "~~").1, "ONe two three");
+		assert_eq!(normal(line, 0, "3rx").1, "xxx two three");
+		assert_eq!(normal(line, 0, "99999999999999999999rx").1, line);
+		assert_eq!(normal(line, 0, "99999999999999999999x").1, "");
+		assert_eq!(normal(line, 0, "ywP").1, "one one two three");
+		assert_eq!(normal(line, 0, "dwwP").1, "two one three");
+
+		let (vi, inp, cursor) = normal(line, 0, "cw");
+		assert!(vi.mode == Mode::Insert);
+		assert_eq!((inp.as_str(), cursor), (" two three", 0));
+	}
+}